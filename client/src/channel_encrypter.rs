@@ -0,0 +1,241 @@
+use common::*;
+use encrypter::{self, MAGIC};
+use error::{ErrBounds, ErrKey, ErrSignature, ErrVersion};
+use openssl::error::ErrorStack;
+use openssl::pkey::PKey;
+use openssl::rand;
+use openssl::rsa::Rsa;
+use openssl::symm::{self, Cipher};
+use rusqlite::Connection as SqlConnection;
+use std::collections::HashMap;
+use std::error::Error;
+use vault;
+
+// Every member encrypts with a key of their own, which is sent to the other members
+// and replaced as soon as somebody joins or leaves.
+const VERSION: u8 = 3;
+
+const SIZE_ID:    usize = 16;
+const SIZE_KEY:   usize = 32;
+const SIZE_NONCE: usize = 12;
+const SIZE_TAG:   usize = 16;
+
+pub fn is_encrypted(input: &[u8]) -> bool {
+    input.len() >= 2 && input[0] == MAGIC && input[1] == VERSION
+}
+
+// Ties the message to the channel, so it can't be reposted somewhere else
+fn associated_data(header: &[u8], channel: usize) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    for i in (0..8).rev() {
+        aad.push((channel as u64 >> (i * 8)) as u8);
+    }
+    aad
+}
+fn seal(id: &[u8], key: &[u8], channel: usize, input: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let mut nonce = [0; SIZE_NONCE];
+    let mut tag   = [0; SIZE_TAG];
+    rand::rand_bytes(&mut nonce)?;
+
+    let mut encrypted = Vec::with_capacity(2+SIZE_ID+SIZE_NONCE+SIZE_TAG+input.len());
+    encrypted.push(MAGIC);
+    encrypted.push(VERSION);
+    encrypted.extend(id);
+
+    let aad = associated_data(&encrypted, channel);
+    let mut encrypted_aes = symm::encrypt_aead(Cipher::aes_256_gcm(), key, Some(&nonce), &aad, input, &mut tag)?;
+
+    encrypted.extend(nonce.iter());
+    encrypted.extend(tag.iter());
+    encrypted.append(&mut encrypted_aes);
+
+    Ok(encrypted)
+}
+fn get_id(input: &[u8]) -> Result<&[u8], Box<Error>> {
+    if input.len() < 2+SIZE_ID+SIZE_NONCE+SIZE_TAG {
+        return Err(Box::new(ErrBounds));
+    }
+    if !is_encrypted(input) {
+        return Err(Box::new(ErrVersion));
+    }
+    Ok(&input[2..2+SIZE_ID])
+}
+fn open(key: &[u8], channel: usize, input: &[u8]) -> Result<Vec<u8>, Box<Error>> {
+    get_id(input)?;
+
+    let (header, input) = input.split_at(2+SIZE_ID);
+    let (nonce, input) = input.split_at(SIZE_NONCE);
+    let (tag, input) = input.split_at(SIZE_TAG);
+
+    let aad = associated_data(header, channel);
+    Ok(symm::decrypt_aead(Cipher::aes_256_gcm(), key, Some(nonce), &aad, input, tag)?)
+}
+
+// A new key, which only becomes the current one once the server confirms it's been published
+pub struct Rotation {
+    pub publish: ChannelKeyPublish,
+    id: Vec<u8>,
+    key: Vec<u8>,
+    members: String
+}
+impl Rotation {
+    // For messages sent while waiting for the confirmation, which may not rotate again
+    pub fn encrypt(&self, input: &[u8]) -> Result<Vec<u8>, Box<Error>> {
+        seal(&self.id, &self.key, self.publish.channel, input)
+    }
+}
+
+fn to_members_string(members: &[usize]) -> String {
+    let mut members = members.to_vec();
+    members.sort_unstable();
+    members.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(",")
+}
+// Creates a new key and encrypts it for every member using their published key
+fn rotate(
+    db: &SqlConnection,
+    server: &str,
+    me: usize,
+    channel: usize,
+    members: &[usize],
+    users: &HashMap<usize, User>
+) -> Result<(Vec<u8>, Vec<u8>, HashMap<usize, Vec<u8>>), Box<Error>> {
+    let mut id  = vec![0; SIZE_ID];
+    let mut key = vec![0; SIZE_KEY];
+    rand::rand_bytes(&mut id)?;
+    rand::rand_bytes(&mut key)?;
+
+    let mut package = id.clone();
+    package.extend(&key);
+
+    let identity = PKey::from_rsa(::get_identity(db)?)?;
+    let mut packages = HashMap::new();
+    for &member in members {
+        if member == me {
+            continue;
+        }
+        let public = match users.get(&member).and_then(|user| user.public_key.as_ref()) {
+            Some(public) => public,
+            None => continue
+        };
+        let rsa = Rsa::public_key_from_pem(public)?;
+        packages.insert(member, encrypter::encrypt(&package, &rsa, &identity)?);
+    }
+
+    // Stored without members, so it's replaced again unless confirm is called
+    db.execute(
+        "INSERT INTO channel_keys (author, channel, id, key, members, server) VALUES (?, ?, ?, ?, '', ?)",
+        &[&(me as i64), &(channel as i64), &id, &vault::seal(db, &key)?, &server]
+    ).unwrap();
+
+    Ok((id, key, packages))
+}
+// Makes a key the current one, after the server confirmed it was sent to the members
+pub fn confirm(db: &SqlConnection, server: &str, me: usize, channel: usize, rotation: &Rotation) {
+    db.execute(
+        "UPDATE channel_keys SET members = ? WHERE server = ? AND channel = ? AND author = ? AND id = ?",
+        &[&rotation.members, &server, &(channel as i64), &(me as i64), &rotation.id]
+    ).unwrap();
+}
+// Encrypts a message, along with a new key to send first if the members have changed since the last one
+pub fn encrypt(
+    db: &SqlConnection,
+    server: &str,
+    me: usize,
+    channel: usize,
+    members: &[usize],
+    users: &HashMap<usize, User>,
+    input: &[u8]
+) -> Result<(Option<Rotation>, Vec<u8>), Box<Error>> {
+    // Users on older clients couldn't read the channel anyway.
+    // Leaving them out means a new key is made once they publish one.
+    let members: Vec<usize> = members.iter()
+        .cloned()
+        .filter(|&member| member == me || users.get(&member).map_or(false, |user| user.public_key.is_some()))
+        .collect();
+    let members_string = to_members_string(&members);
+
+    let current: Option<(Vec<u8>, Vec<u8>, String)> = db.query_row(
+        "SELECT id, key, members FROM channel_keys WHERE server = ? AND channel = ? AND author = ?
+        ORDER BY rowid DESC LIMIT 1",
+        &[&server, &(channel as i64), &(me as i64)],
+        |row| (row.get(0), row.get(1), row.get(2))
+    ).ok();
+
+    let mut rotation = None;
+    let (id, key) = match current {
+        Some((id, key, current)) if current == members_string => (id, vault::open(db, &key)?),
+        _ => {
+            let (id, key, packages) = rotate(db, server, me, channel, &members, users)?;
+            rotation = Some(Rotation {
+                publish: ChannelKeyPublish {
+                    channel: channel,
+                    packages: packages
+                },
+                id: id.clone(),
+                key: key.clone(),
+                members: members_string
+            });
+            (id, key)
+        }
+    };
+
+    Ok((rotation, seal(&id, &key, channel, input)?))
+}
+pub fn decrypt(db: &SqlConnection, server: &str, channel: usize, author: usize, input: &[u8]) -> Result<Vec<u8>, Box<Error>> {
+    let id = get_id(input)?;
+
+    // Looked up by author too, so nobody can pass off their messages as someone else's
+    let key: Vec<u8> = db.query_row(
+        "SELECT key FROM channel_keys WHERE server = ? AND channel = ? AND author = ? AND id = ?",
+        &[&server, &(channel as i64), &(author as i64), &id],
+        |row| row.get(0)
+    ).map_err(|_| ErrKey)?;
+
+    open(&vault::open(db, &key)?, channel, input)
+}
+// Stores a key sent by another member
+pub fn receive(db: &SqlConnection, server: &str, event: &ChannelKeyReceive, sender: Option<&PKey>) -> Result<(), Box<Error>> {
+    // Legacy encryption can't tell who sent it
+    if encrypter::is_legacy(&event.data) {
+        return Err(Box::new(ErrSignature));
+    }
+    let identity = ::get_identity(db)?;
+    let package = encrypter::decrypt(&event.data, &identity, sender)?;
+    if package.len() != SIZE_ID+SIZE_KEY {
+        return Err(Box::new(ErrBounds));
+    }
+    let (id, key) = package.split_at(SIZE_ID);
+
+    db.execute(
+        "INSERT OR IGNORE INTO channel_keys (author, channel, id, key, members, server) VALUES (?, ?, ?, ?, '', ?)",
+        &[&(event.author as i64), &(event.channel as i64), &id, &vault::seal(db, key)?, &server]
+    ).unwrap();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let id  = [1; SIZE_ID];
+        let key = [2; SIZE_KEY];
+
+        let encrypted = seal(&id, &key, 1, b"Hello, channel!").unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert_eq!(get_id(&encrypted).unwrap(), &id);
+        assert_eq!(open(&key, 1, &encrypted).unwrap(), b"Hello, channel!");
+
+        // Moved to another channel
+        assert!(open(&key, 2, &encrypted).is_err());
+        assert!(open(&[3; SIZE_KEY], 1, &encrypted).is_err());
+
+        for i in 0..encrypted.len() {
+            let mut modified = encrypted.clone();
+            modified[i] ^= 1;
+            assert!(open(&key, 1, &modified).is_err());
+        }
+    }
+}
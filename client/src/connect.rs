@@ -8,6 +8,7 @@ use frontend;
 pub fn connect(
     addr: SocketAddr,
     db: &SqlConnection,
+    invite: Option<&str>,
     nick: &str,
     screen: &frontend::Screen,
     ssl: &SslConnector
@@ -43,7 +44,10 @@ pub fn connect(
     if let Some(row) = rows.next() {
         let row = row.unwrap();
         public_key = row.get(0);
-        token = row.get(1);
+        let encrypted: Option<Vec<u8>> = row.get(1);
+        token = encrypted
+            .and_then(|token| vault::open(db, &token).ok())
+            .and_then(|token| String::from_utf8(token).ok());
     } else {
         println!("To securely connect, data from the server (\"public key\") is needed.");
         println!("You can obtain the \"public key\" from the server owner.");
@@ -97,6 +101,8 @@ config.danger_connect_without_providing_domain_for_certificate_verification_and_
     if let Some(token) = token {
         let packet = Packet::Login(common::Login {
             bot: false,
+            device: Some(device_label()),
+            invite: invite.map(String::from),
             name: nick.to_string(),
             password: None,
             token: Some(token.to_string())
@@ -116,6 +122,11 @@ config.danger_connect_without_providing_domain_for_certificate_verification_and_
                     println!("I think you should stay away from this server. It's weird.");
                     return None;
                 }
+                // The server may hand out a fresh token when the old one is getting old
+                if let Err(err) = store_token(db, &addr.to_string(), &login.token) {
+                    println!("Failed to save your login token");
+                    println!("{}", err);
+                }
                 println!("Logged in as user #{}", login.id);
             },
             Ok(Packet::Err(code)) => match code {
@@ -133,6 +144,10 @@ config.danger_connect_without_providing_domain_for_certificate_verification_and_
                     println!("This account is a bot account");
                     return None;
                 },
+                common::ERR_LOGIN_LOCKED => {
+                    println!("Too many failed login attempts. Try again later.");
+                    return None;
+                },
                 common::ERR_MAX_CONN_PER_IP => {
                     println!("Too many connections made from this IP");
                     return None;
@@ -162,6 +177,8 @@ config.danger_connect_without_providing_domain_for_certificate_verification_and_
 
         let packet = Packet::Login(common::Login {
             bot: false,
+            device: Some(device_label()),
+            invite: invite.map(String::from),
             name: nick.to_string(),
             password: Some(pass),
             token: None
@@ -175,10 +192,10 @@ config.danger_connect_without_providing_domain_for_certificate_verification_and_
 
         match common::read(&mut stream) {
             Ok(Packet::LoginSuccess(login)) => {
-                db.execute(
-                    "UPDATE servers SET token = ? WHERE ip = ?",
-                    &[&login.token, &addr.to_string()]
-                ).unwrap();
+                if let Err(err) = store_token(db, &addr.to_string(), &login.token) {
+                    println!("Failed to save your login token");
+                    println!("{}", err);
+                }
                 if login.created {
                     println!("Account created");
                 }
@@ -198,10 +215,28 @@ config.danger_connect_without_providing_domain_for_certificate_verification_and_
                     println!("This account is a bot account");
                     return None;
                 },
+                common::ERR_INVITE_INVALID => {
+                    println!("This server requires a valid invite to register.");
+                    println!("Usage: /connect <ip[:port]> <invite>");
+                    return None;
+                },
                 common::ERR_LOGIN_INVALID => {
                     println!("Invalid credentials");
                     return None;
                 },
+                common::ERR_LOGIN_LOCKED => {
+                    println!("Too many failed login attempts. Try again later.");
+                    return None;
+                },
+                common::ERR_PASSWORD_WEAK => {
+                    println!("That password is too weak for this server.");
+                    println!("Try a longer one with more kinds of characters.");
+                    return None;
+                },
+                common::ERR_REGISTRATION_CLOSED => {
+                    println!("This server does not accept new accounts");
+                    return None;
+                },
                 _ => {
                     println!("The server responded with an invalid error. :/");
                     return None;
@@ -218,8 +253,22 @@ config.danger_connect_without_providing_domain_for_certificate_verification_and_
             }
         }
     }
+
+    publish_keys(db, &addr, screen, &mut stream, false);
+
     stream.get_ref().set_nonblocking(true).expect("Failed to make stream non-blocking");
-    Some(Session::new(addr, id.unwrap(), stream))
+    let mut session = Session::new(addr, id.unwrap(), stream);
+    {
+        let mut stmt = db.prepare_cached("SELECT DISTINCT peer FROM pm_history WHERE server = ?").unwrap();
+        let mut rows = stmt.query(&[&addr.to_string()]).unwrap();
+
+        while let Some(row) = rows.next() {
+            let row = row.unwrap();
+            let peer: i64 = row.get(0);
+            session.pm_unread.insert(peer as usize, 0);
+        }
+    }
+    Some(session)
 }
 
 pub fn reconnect(
@@ -232,7 +281,7 @@ pub fn reconnect(
 ) {
     if err.kind() == std::io::ErrorKind::BrokenPipe {
         screen.log(String::from("Attempting reconnect..."));
-        if let Some(new) = connect(session.addr, db, nick, screen, ssl) {
+        if let Some(new) = connect(session.addr, db, None, nick, screen, ssl) {
             *session = new;
         }
     }
@@ -256,3 +305,73 @@ pub fn write(
     }
     true
 }
+// Publishes the identity key so others can send private messages without any setup.
+// Unless `rotate` is set, the server refuses to replace keys published by another device.
+pub fn publish_keys(
+    db: &SqlConnection,
+    addr: &SocketAddr,
+    screen: &frontend::Screen,
+    stream: &mut SslStream<TcpStream>,
+    rotate: bool
+) {
+    macro_rules! println {
+        () => { screen.log(String::new()); };
+        ($arg:expr) => { screen.log(String::from($arg)); };
+        ($($arg:expr),*) => { screen.log(format!($($arg),*)); };
+    }
+
+    let public_key = match get_identity(db).and_then(|rsa| Ok(rsa.public_key_to_pem()?)) {
+        Ok(public_key) => public_key,
+        Err(err) => {
+            println!("Failed to load your identity key");
+            println!("{}", err);
+            return;
+        }
+    };
+    // A rotated key takes its prekeys along, so the server never mixes those of two devices.
+    // Otherwise the server asks for prekeys whenever it's running low.
+    let prekeys = if rotate {
+        match generate_prekeys(db, addr) {
+            Ok(prekeys) => Some(prekeys),
+            Err(err) => {
+                println!("Failed to generate prekeys");
+                println!("{}", err);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
+    let packet = Packet::KeyPublish(common::KeyPublish {
+        prekeys: prekeys,
+        public_key: public_key,
+        rotate: rotate
+    });
+    if let Err(err) = common::write(stream, &packet) {
+        println!("Failed to publish your identity key");
+        println!("{}", err);
+    }
+}
+// Prekeys let others start forward secret sessions, even while you're offline
+pub fn publish_prekeys(
+    db: &SqlConnection,
+    addr: &SocketAddr,
+    screen: &frontend::Screen,
+    stream: &mut SslStream<TcpStream>
+) {
+    match generate_prekeys(db, addr) {
+        Ok(prekeys) => if let Err(err) = common::write(stream, &Packet::PrekeyPublish(prekeys)) {
+            screen.log(String::from("Failed to publish your prekeys"));
+            screen.log(format!("{}", err));
+        },
+        Err(err) => {
+            screen.log(String::from("Failed to generate prekeys"));
+            screen.log(format!("{}", err));
+        }
+    }
+}
+fn generate_prekeys(db: &SqlConnection, addr: &SocketAddr) -> Result<common::PrekeyPublish, Box<std::error::Error>> {
+    let identity = openssl::pkey::PKey::from_rsa(get_identity(db)?)?;
+    ratchet::publish(db, &addr.to_string(), &identity)
+}
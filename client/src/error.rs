@@ -0,0 +1,32 @@
+// Errors shared by the encryption modules and the vault
+
+use std::error::Error;
+use std::fmt;
+
+macro_rules! error {
+    ($name:ident, $description:expr) => {
+        #[derive(Debug)]
+        pub struct $name;
+
+        impl Error for $name {
+            fn description(&self) -> &'static str {
+                $description
+            }
+        }
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}", self.description())
+            }
+        }
+    }
+}
+
+error!(ErrBounds, "Input out of bounds");
+error!(ErrKey, "Missing the key this was encrypted with");
+error!(ErrLocked, "Your local data is locked");
+error!(ErrMembers, "Haven't received the channel members yet");
+error!(ErrPublicKey, "Invalid public key");
+error!(ErrSession, "No encrypted session with this user");
+error!(ErrSignature, "Could not verify who sent this");
+error!(ErrSkip, "Too many messages were skipped");
+error!(ErrVersion, "Unsupported encryption format");
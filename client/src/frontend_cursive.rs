@@ -6,6 +6,7 @@ use self::cursive::view::{Identifiable, ScrollStrategy};
 use self::cursive::views::*;
 use std::boxed::FnBox;
 use std::cell::RefCell;
+use std::io::{self, Write};
 use std::rc::Rc;
 use std::sync::mpsc;
 use std::sync::{Mutex, RwLock};
@@ -49,6 +50,10 @@ impl Screen {
             cursive.add_fullscreen_layer(
                 LinearLayout::horizontal()
                     .child(LinearLayout::vertical()
+                        .child(BoxView::with_full_width(
+                            TextView::empty()
+                                .with_id("topic")
+                        ))
                         .child(BoxView::with_full_screen(
                             TextView::empty()
                                 .scrollable(true)
@@ -149,6 +154,11 @@ impl Screen {
         }
         // TODO: remove_item once stable
     }
+    pub fn bell(&self) {
+        // Cursive has no way to do this, but the terminal still listens to stdout
+        print!("\x07");
+        let _ = io::stdout().flush();
+    }
 
     pub fn repaint(&self) {
         self.repaint_(&**self.log.read().unwrap());
@@ -189,30 +199,81 @@ impl Screen {
         })).unwrap();
     }
     pub fn update(&self, session: &Session) {
-        let mut names: Vec<_> = session.channels.values()
-            .map(|c| {
-                let mut name = String::with_capacity(1 + c.name.len());
-                name.push('#');
-                name.push_str(&c.name); // God forgive me for cloning
+        let categories: Vec<_> = get_channel_categories(&session.channels).into_iter()
+            .map(|(category, channels)| {
+                let names: Vec<_> = channels.iter()
+                    .map(|c| {
+                        let mut name = String::with_capacity(1 + c.name.len());
+                        name.push('#');
+                        name.push_str(&c.name); // God forgive me for cloning
+                        name.push_str(&to_unread_string(session.unread.get(&c.id)));
+                        name
+                    })
+                    .collect();
+                (category.map(|category| sanitize(category.to_string())), names)
+            })
+            .collect();
+        let mut pms: Vec<_> = session.pm_unread.iter()
+            .filter_map(|(id, unread)| session.users.get(id).map(|user| (user, *unread)))
+            .collect();
+        pms.sort_by(|&(a, _), &(b, _)| a.name.cmp(&b.name));
+        let pms: Vec<_> = pms.into_iter()
+            .map(|(user, unread)| {
+                let mut name = sanitize(user.name.clone());
+                if unread > 0 {
+                    name.push_str(&format!(" ({})", unread));
+                }
                 name
             })
             .collect();
-        names.sort();
+
+        let topic = if let Some(user) = session.query.and_then(|id| session.users.get(&id)) {
+            format!("Private messages with {}", sanitize(user.name.clone()))
+        } else {
+            session.channel
+                .and_then(|id| session.channels.get(&id))
+                .map(|channel| {
+                    let mut topic = format!("#{}", channel.name);
+                    if let Some(ref text) = channel.topic {
+                        topic.push_str(": ");
+                        topic.push_str(&sanitize(text.clone()));
+                    }
+                    topic
+                })
+                .unwrap_or_default()
+        };
 
         self.sink.lock().unwrap().send(Box::new(move |cursive: &mut Cursive| {
+            cursive.call_on_id("topic", move |view: &mut TextView| {
+                view.set_content(topic);
+            });
             cursive.call_on_id("channels", |list: &mut ListView| {
                 list.clear();
                 list.add_child("Channels", DummyView);
                 list.add_delimiter();
-                for name in &*names {
-                    list.add_child(name, DummyView);
+                for &(ref category, ref names) in &categories {
+                    if let Some(ref category) = *category {
+                        list.add_delimiter();
+                        list.add_child(category, DummyView);
+                    }
+                    for name in names {
+                        list.add_child(name, DummyView);
+                    }
+                }
+                if !pms.is_empty() {
+                    list.add_delimiter();
+                    list.add_child("Private messages", DummyView);
+                    list.add_delimiter();
+                    for name in &pms {
+                        list.add_child(name, DummyView);
+                    }
                 }
             });
         })).unwrap();
     }
 
-    pub fn get_channel_overrides(&self, overrides: HashMap<usize, (u8, u8)>, session: &Session)
-            -> Result<HashMap<usize, (u8, u8)>, ()> {
+    pub fn get_channel_overrides(&self, overrides: HashMap<usize, (u16, u16)>, session: &Session)
+            -> Result<HashMap<usize, (u16, u16)>, ()> {
         let names: HashMap<_, _> = session.groups.iter()
             .map(|(id, group)| {
                 let mut name = id.to_string();
@@ -223,7 +284,7 @@ impl Screen {
             })
             .collect();
 
-        let (tx, rx) = mpsc::channel::<HashMap<usize, (u8, u8)>>();
+        let (tx, rx) = mpsc::channel::<HashMap<usize, (u16, u16)>>();
 
         self.sink.lock().unwrap().send(Box::new(move |cursive: &mut Cursive| {
             let group_read    = Rc::new(RefCell::new(RadioGroup::new()));
@@ -415,7 +476,7 @@ fn bitmask_for_buttons(
     read:    &RadioGroup<String>,
     write:   &RadioGroup<String>,
     channel: &RadioGroup<String>
-) -> (u8, u8) {
+) -> (u16, u16) {
     let mut allow = 0;
     let mut deny = 0;
 
@@ -430,7 +491,7 @@ fn bitmask_for_buttons(
 
     (allow, deny)
 }
-fn buttons_for_bitmask(bitmask: (u8, u8), cursive: &mut Cursive) {
+fn buttons_for_bitmask(bitmask: (u16, u16), cursive: &mut Cursive) {
     let (allow, deny) = bitmask;
 
     macro_rules! toggle {
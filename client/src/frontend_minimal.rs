@@ -91,6 +91,11 @@ impl Screen {
         }
         // TODO: remove_item once stable
     }
+    pub fn bell(&self) {
+        let mut stdout = self.stdout.lock().unwrap();
+        write!(stdout, "\x07").unwrap();
+        stdout.flush().unwrap();
+    }
 
     pub fn repaint(&self) {
         if self.mute.load(AtomicOrdering::Relaxed) {
@@ -225,8 +230,8 @@ impl Screen {
     }
     pub fn update(&self, _: &Session) {}
 
-    pub fn get_channel_overrides(&self, mut overrides: HashMap<usize, (u8, u8)>, session: &Session)
-            -> Result<HashMap<usize, (u8, u8)>, ()> {
+    pub fn get_channel_overrides(&self, mut overrides: HashMap<usize, (u16, u16)>, session: &Session)
+            -> Result<HashMap<usize, (u16, u16)>, ()> {
         let _guard = self.mute();
         let log = Vec::with_capacity(2);
 
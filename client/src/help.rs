@@ -1,6 +1,13 @@
 pub fn help(query: &[&str], screen: &::frontend::Screen) {
     let all = query.is_empty();
 
+    if all || query.contains(&"account") {
+        screen.log("\
+            account <delete/export <path>>\n\
+            \"delete\" permanently deletes your account on the current server.\n\
+            \"export\" saves everything the server knows about you to <path> as JSON.\
+        ".to_string());
+    }
     if all || query.contains(&"ban") || query.contains(&"unban") {
         screen.log("\
             ban/unban <user>\n\
@@ -9,11 +16,12 @@ pub fn help(query: &[&str], screen: &::frontend::Screen) {
     }
     if all || query.contains(&"connect") {
         let mut text = String::from("\
-            connect <ip[:port]>\n\
+            connect <ip[:port]> [invite]\n\
             Connects to IP. Default port is \
         ");
         text.push_str(&::common::DEFAULT_PORT.to_string());
-        text.push('.');
+        text.push_str(".\n\
+            [invite] is only needed to create an account on an invite-only server.");
         screen.log(text);
     }
     if all || query.contains(&"create") {
@@ -21,7 +29,10 @@ pub fn help(query: &[&str], screen: &::frontend::Screen) {
             create <\"channel\"/\"group\"> <name> [data]\n\
             Creates a channel/group with <name>.\n\
             If it's a group, it may optionally take [data] as a permission string\n\
-            to prevent having to edit it later.\
+            to prevent having to edit it later.\n\
+            If it's a channel, [data] may be \"encrypted\" to encrypt messages end-to-end.\n\
+            Only members can read them, and only those sent after they joined.\n\
+            Attachments are not encrypted.\
         ".to_string());
     }
     if all || query.contains(&"delete") {
@@ -36,6 +47,19 @@ pub fn help(query: &[&str], screen: &::frontend::Screen) {
             Disconnects from the currently connected server.\
         ".to_string());
     }
+    if all || query.contains(&"download") {
+        screen.log("\
+            download <id> <path>\n\
+            Downloads the attachment with <id> and saves it to <path>.\
+        ".to_string());
+    }
+    if all || query.contains(&"fingerprint") {
+        screen.log("\
+            fingerprint [user]\n\
+            Prints the fingerprint of your identity key, or the one [user] published.\n\
+            Compare it in person or through another channel to make sure nobody reads your private messages.\
+        ".to_string());
+    }
     if all || query.contains(&"forget") {
         screen.log("\
             forget <ip[:port]>\n\
@@ -57,6 +81,13 @@ pub fn help(query: &[&str], screen: &::frontend::Screen) {
             Useful for getting the ID for functions that require such.\
         ".to_string());
     }
+    if all || query.contains(&"invite") {
+        screen.log("\
+            invite <create [max uses] [max age]/list>\n\
+            Creates an invite code others can use to register on the current server.\n\
+            [max age] is in seconds. \"list\" shows all your invites that are still valid.\
+        ".to_string());
+    }
     if all || query.contains(&"join") {
         screen.log("\
             join <channel>\n\
@@ -69,11 +100,26 @@ pub fn help(query: &[&str], screen: &::frontend::Screen) {
             Lists all <\"channels\"/\"groups\"/\"users\">.\
         ".to_string());
     }
+    if all || query.contains(&"lock") {
+        screen.log("\
+            lock\n\
+            Disconnects and locks your data until you enter your passphrase again.\n\
+            You're reconnected once it's unlocked. See /passphrase.\
+        ".to_string());
+    }
+    if all || query.contains(&"move") {
+        screen.log("\
+            move <channel> <position>\n\
+            Moves <channel> to <position> in the channel list, starting at 1.\
+        ".to_string());
+    }
     if all || query.contains(&"msg") {
         screen.log("\
             msg <user> <message>\n\
             Sends <message> in encrypted form privately to <user>.\n\
-            See /setupkeys.\
+            The first message sets up a forward secret session using the keys\n\
+            <user> published automatically, even if they're offline. See /fingerprint.\n\
+            Use /query to see the conversation so far.\
         ".to_string());
     }
     if all || query.contains(&"nick") {
@@ -84,22 +130,106 @@ pub fn help(query: &[&str], screen: &::frontend::Screen) {
             It does *not* update all of your servers.\
         ".to_string());
     }
+    if all || query.contains(&"owner") {
+        screen.log("\
+            owner <add/transfer> <user>\n\
+            Makes <user> an owner of the current server. Only owners can do this.\n\
+            \"add\" keeps you as an owner as well, \"transfer\" hands it over completely.\
+        ".to_string());
+    }
+    if all || query.contains(&"passphrase") {
+        screen.log("\
+            passphrase\n\
+            Sets, changes or removes the passphrase that encrypts your login tokens,\n\
+            private keys and private messages on this computer.\n\
+            You'll have to enter it every time you start the client.\
+        ".to_string());
+    }
     if all || query.contains(&"passwd") {
         screen.log("\
             passwd\n\
             Changes password on the current server.\
         ".to_string());
     }
+    if all || query.contains(&"pin") || query.contains(&"unpin") {
+        screen.log("\
+            pin/unpin <id>\n\
+            Pins or unpins the message with <id> in its channel.\
+        ".to_string());
+    }
+    if all || query.contains(&"pins") {
+        screen.log("\
+            pins\n\
+            Lists all pinned messages in the current channel.\
+        ".to_string());
+    }
+    if all || query.contains(&"profile") {
+        screen.log("\
+            profile\n\
+            Interactively edits your display name, pronouns, timezone and bio on the current server.\n\
+            Others can see them with /info.\
+        ".to_string());
+    }
+    if all || query.contains(&"query") {
+        screen.log("\
+            query [user]\n\
+            Opens your private conversation with <user>, showing its history.\n\
+            Anything you type is sent to <user> until you /join a channel.\n\
+            Without arguments, closes the conversation.\
+        ".to_string());
+    }
     if all || query.contains(&"quit") {
         screen.log("\
             quit\n\
             Quits the application.\
         ".to_string());
     }
+    if all || query.contains(&"react") || query.contains(&"unreact") {
+        screen.log("\
+            react/unreact <id> <emoji>\n\
+            Adds or removes your <emoji> reaction on the message with <id>.\
+        ".to_string());
+    }
+    if all || query.contains(&"rotatekey") {
+        screen.log("\
+            rotatekey\n\
+            Publishes this device's keys, replacing the ones another device of yours published.\n\
+            Private messages will arrive here instead, and sessions with the old keys stop working.\
+        ".to_string());
+    }
+    if all || query.contains(&"search") {
+        screen.log("\
+            search <query> [in:channel] [from:user] [before:time] [after:time]\n\
+            Searches all readable channels for messages containing every word in <query>.\n\
+            Filters may be used to narrow down the results. Times are UNIX timestamps.\n\
+            Encrypted channels can't be searched.\
+        ".to_string());
+    }
+    if all || query.contains(&"sessions") {
+        screen.log("\
+            sessions [revoke <id>]\n\
+            Lists all devices logged in to your account on the current server.\n\
+            \"revoke\" logs out the session with <id>.\
+        ".to_string());
+    }
     if all || query.contains(&"setupkeys") {
         screen.log("\
             setupkeys <user>\n\
-            Prepares for encrypted messaging with /msg.\
+            Exchanges keys for /msg by hand, for users with clients that don't publish one.\
+        ".to_string());
+    }
+    if all || query.contains(&"status") {
+        screen.log("\
+            status <online/dnd> [text]\n\
+            Sets your status on the current server, shown next to your name in /list users.\n\
+            \"dnd\" tells others not to disturb you. Leaving out [text] clears it.\
+        ".to_string());
+    }
+    if all || query.contains(&"upload") {
+        screen.log("\
+            upload <path>\n\
+            Uploads the file at <path> as an attachment.\n\
+            It will be sent along with your next message.\
         ".to_string());
     }
     if all || query.contains(&"update") {
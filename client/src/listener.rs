@@ -1,7 +1,7 @@
 use *;
 use common::Packet;
 use rusqlite::Connection as SqlConnection;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -24,6 +24,8 @@ pub fn listen(
 
     let mut typing_last = Instant::now();
     let typing_check = Duration::from_secs(1);
+    // The newest message seen in the current channel, acknowledged once a second
+    let mut ack = None;
 
     let mut size = true;
     let mut buf = vec![0; 2];
@@ -53,6 +55,16 @@ pub fn listen(
                     });
 
                 screen.typing_set(get_typing_string(people, session.typing.len()));
+
+                if let Some((channel, message)) = ack.take() {
+                    let packet = Packet::AckChannel(common::AckChannel {
+                        channel: channel,
+                        message: message
+                    });
+                    if let Err(err) = common::write(&mut session.stream, &packet) {
+                        println!("Failed to mark channel as read: {}", err);
+                    }
+                }
             }
             match session.stream.read(&mut buf[i..]) {
                 Ok(0) => continue,
@@ -70,8 +82,121 @@ pub fn listen(
                             match common::deserialize(&buf) {
                                 Ok(packet) => {
                                     match packet {
+                                        Packet::Err(_) |
+                                        Packet::RateLimited(..) if !session.pending_keys.is_empty() => {
+                                            // Maybe the key was refused, and then nobody could read the messages
+                                            let dropped: usize = session.pending_keys.drain()
+                                                .map(|(_, (_, queue))| queue.len())
+                                                .sum();
+                                            println!("{} message(s) waiting for a new channel key weren't sent", dropped);
+                                        },
+                                        _ => ()
+                                    }
+                                    match packet {
+                                        Packet::AccountExportReceive(event) => {
+                                            let next = event.offset + event.data.len();
+                                            let mut done = next >= event.size;
+                                            if let Some(ref mut file) = session.export {
+                                                if let Err(err) = file.write_all(&event.data) {
+                                                    println!("Failed to write account export");
+                                                    println!("{}", err);
+                                                    done = true;
+                                                } else if done {
+                                                    println!("Exported account data");
+                                                }
+                                            } else {
+                                                done = true;
+                                            }
+                                            if done {
+                                                session.export = None;
+                                            } else {
+                                                let packet = Packet::AccountExport(common::AccountExport {
+                                                    offset: next
+                                                });
+                                                if let Err(err) = common::write(&mut session.stream, &packet) {
+                                                    println!("Failed to export account data");
+                                                    println!("{}", err);
+                                                    session.export = None;
+                                                }
+                                            }
+                                        },
+                                        Packet::AttachmentChunkReceive(event) => {
+                                            let next = event.offset + event.data.len();
+                                            let mut done = next >= event.size;
+                                            if let Some(file) = session.downloads.get_mut(&event.id) {
+                                                if let Err(err) = file.write_all(&event.data) {
+                                                    println!("Failed to write attachment #{}", event.id);
+                                                    println!("{}", err);
+                                                    done = true;
+                                                } else if done {
+                                                    println!("Downloaded attachment #{}", event.id);
+                                                }
+                                            } else {
+                                                done = true;
+                                            }
+                                            if done {
+                                                session.downloads.remove(&event.id);
+                                            } else {
+                                                let packet = Packet::AttachmentDownload(common::AttachmentDownload {
+                                                    id: event.id,
+                                                    offset: next
+                                                });
+                                                if let Err(err) = common::write(&mut session.stream, &packet) {
+                                                    println!("Failed to download attachment #{}", event.id);
+                                                    println!("{}", err);
+                                                    session.downloads.remove(&event.id);
+                                                }
+                                            }
+                                        },
+                                        Packet::AttachmentReceive(event) => {
+                                            if event.inner.author == session.id {
+                                                session.attachments.push(event.inner.id);
+                                                println!(
+                                                    "Uploaded \"{}\" as attachment #{}. It will be sent with your next message.",
+                                                    event.inner.name,
+                                                    event.inner.id
+                                                );
+                                            }
+                                        },
                                         Packet::ChannelDeleteReceive(event) => {
                                             session.channels.remove(&event.inner.id);
+                                            session.members.remove(&event.inner.id);
+                                        },
+                                        Packet::ChannelKeyDeliveryReceive(event) => {
+                                            if let Some((rotation, queue)) = session.pending_keys.remove(&event.channel) {
+                                                ::channel_encrypter::confirm(
+                                                    &db.lock().unwrap(),
+                                                    &session.addr.to_string(),
+                                                    session.id,
+                                                    event.channel,
+                                                    &rotation
+                                                );
+                                                for packet in queue {
+                                                    if let Err(err) = common::write(&mut session.stream, &packet) {
+                                                        println!("Failed to send message");
+                                                        println!("{}", err);
+                                                        break;
+                                                    }
+                                                }
+                                            }
+                                        },
+                                        Packet::ChannelKeyReceive(event) => {
+                                            let sender = get_user_key(session, event.author);
+                                            let result = ::channel_encrypter::receive(
+                                                &db.lock().unwrap(),
+                                                &session.addr.to_string(),
+                                                &event,
+                                                sender.as_ref()
+                                            );
+                                            if let Err(err) = result {
+                                                let user = session.users.get(&event.author)
+                                                    .map(|user| &*user.name)
+                                                    .unwrap_or("unknown");
+                                                println!("Failed to receive the key {} uses in channel #{}: {}", user, event.channel, err);
+                                            }
+                                        },
+                                        Packet::ChannelMembersReceive(event) => {
+                                            session.members.insert(event.channel, event.members);
                                         },
                                         Packet::ChannelReceive(event) => {
                                             session.channels.insert(event.inner.id, event.inner);
@@ -113,80 +238,427 @@ pub fn listen(
                                             }
                                             session.groups.insert(event.inner.id, event.inner);
                                         },
+                                        Packet::InviteReceive(event) => {
+                                            let invite = event.inner;
+                                            println!(
+                                                "Invite {}: used {}/{} times, {}",
+                                                invite.code,
+                                                invite.uses,
+                                                invite.max_uses.map(|uses| uses.to_string())
+                                                    .unwrap_or_else(|| String::from("unlimited")),
+                                                invite.expires.map(|expires| format!("expires at {}", expires))
+                                                    .unwrap_or_else(|| String::from("never expires"))
+                                            );
+                                        },
                                         Packet::LoginSuccess(event) => {
-                                            db.lock().unwrap().execute(
-                                                "UPDATE servers SET token = ? WHERE ip = ?",
-                                                &[&event.token, &session.addr.to_string()]
-                                            ).unwrap();
+                                            if let Err(err) = store_token(&db.lock().unwrap(), &session.addr.to_string(), &event.token) {
+                                                println!("Failed to save your login token");
+                                                println!("{}", err);
+                                            }
+                                        },
+                                        Packet::MentionReceive(event) => {
+                                            let msg = event.inner;
+                                            screen.bell();
+
+                                            if session.channel != Some(msg.channel) {
+                                                session.unread.entry(msg.channel)
+                                                    .or_insert_with(common::UnreadReceive::default)
+                                                    .mentions += 1;
+                                                screen.update(session);
+
+                                                let user = session.users.get(&msg.author)
+                                                    .map(|user| &*user.name)
+                                                    .unwrap_or("[deleted]");
+                                                let channel = session.channels.get(&msg.channel)
+                                                    .map(|channel| &*channel.name)
+                                                    .unwrap_or("unknown");
+                                                screen.log(format!(
+                                                    "{} mentioned you in #{}: {}",
+                                                    user,
+                                                    channel,
+                                                    frontend::sanitize(String::from_utf8_lossy(&msg.text).into_owned())
+                                                ));
+                                            }
                                         },
                                         Packet::MessageDeleteReceive(event) => {
                                             screen.delete(LogEntryId::Message(event.id));
                                             screen.repaint();
                                         },
                                         Packet::MessageReceive(msg) => {
-                                            let msg = msg.inner;
+                                            // Edits are also sent as new messages
+                                            let new = msg.new && msg.inner.timestamp_edit.is_none();
+                                            let mut msg = msg.inner;
+                                            decrypt_message(&db.lock().unwrap(), session, &mut msg);
                                             session.typing.remove(&(msg.author, msg.channel));
 
-                                            if let Some(user) = session.users.get(&msg.author) {
-                                                if session.channel == Some(msg.channel) {
-                                                    screen.log_with_id(
-                                                        format!(
-                                                            "{} (ID #{}): {}",
-                                                            user.name,
-                                                            msg.id,
-                                                            frontend::sanitize(
-                                                                String::from_utf8_lossy(&msg.text)
-                                                                    .into_owned()
-                                                            )
+                                            if session.channel == Some(msg.channel) {
+                                                {
+                                                    let unread = session.unread.entry(msg.channel)
+                                                        .or_insert_with(common::UnreadReceive::default);
+                                                    if unread.last_read.map(|last| msg.id > last).unwrap_or(true) {
+                                                        unread.last_read = Some(msg.id);
+                                                        unread.mentions = 0;
+                                                        unread.unread = 0;
+                                                        ack = Some((msg.channel, msg.id));
+                                                    }
+                                                }
+                                                // Messages by deleted accounts may be kept around anonymized
+                                                let user = session.users.get(&msg.author)
+                                                    .map(|user| &*user.name)
+                                                    .unwrap_or("[deleted]");
+                                                let highlight = if msg.author != session.id && is_mentioned(session, &msg) {
+                                                    "[!] "
+                                                } else {
+                                                    ""
+                                                };
+                                                screen.log_with_id(
+                                                    format!(
+                                                        "{}{} (ID #{}): {}{}{}",
+                                                        highlight,
+                                                        user,
+                                                        msg.id,
+                                                        frontend::sanitize(
+                                                            String::from_utf8_lossy(&msg.text)
+                                                                .into_owned()
                                                         ),
-                                                        LogEntryId::Message(msg.id)
-                                                    );
+                                                        to_attachment_string(&msg.attachments),
+                                                        to_reaction_string(&msg.reactions)
+                                                    ),
+                                                    LogEntryId::Message(msg.id)
+                                                );
+                                            } else if new && msg.author != session.id {
+                                                session.unread.entry(msg.channel)
+                                                    .or_insert_with(common::UnreadReceive::default)
+                                                    .unread += 1;
+                                                screen.update(session);
+                                            }
+                                            if msg.author == session.id {
+                                                session.last = Some((msg.id, msg.text));
+                                            }
+                                        },
+                                        Packet::PinDeleteReceive(event) => {
+                                            if session.channel == Some(event.channel) {
+                                                println!("Message #{} was unpinned", event.id);
+                                            }
+                                        },
+                                        Packet::PinReceive(event) => {
+                                            let mut msg = event.inner;
+                                            decrypt_message(&db.lock().unwrap(), session, &mut msg);
+                                            if session.channel == Some(msg.channel) {
+                                                let user = session.users.get(&msg.author)
+                                                    .map(|user| &*user.name)
+                                                    .unwrap_or("unknown");
+                                                println!(
+                                                    "{} {} (ID #{}): {}",
+                                                    if event.new { "Pinned message from" } else { "Pin:" },
+                                                    user,
+                                                    msg.id,
+                                                    frontend::sanitize(
+                                                        String::from_utf8_lossy(&msg.text)
+                                                            .into_owned()
+                                                    )
+                                                );
+                                            }
+                                        },
+                                        Packet::PMDeliveryReceive(event) => {
+                                            let user = session.users.get(&event.recipient)
+                                                .map(|user| &*user.name)
+                                                .unwrap_or("unknown");
+                                            if event.delivered {
+                                                println!("Delivered to {}", user);
+                                            } else {
+                                                println!("{} is offline, they will get it when they log in", user);
+                                            }
+                                        },
+                                        Packet::PMReceive(msg) => {
+                                            use openssl::rsa::Rsa;
+                                            let sender = get_user_key(session, msg.author);
+                                            let redelivered = msg.id
+                                                .map(|id| is_pm_stored(&db.lock().unwrap(), &session.addr.to_string(), id))
+                                                .unwrap_or(false);
+
+                                            let decrypted = if redelivered {
+                                                None
+                                            } else {
+                                                let db = db.lock().unwrap();
+                                                let mut decrypted = if ::ratchet::is_ratchet(&msg.text) {
+                                                    ::ratchet::decrypt(
+                                                        &db,
+                                                        &session.addr.to_string(),
+                                                        msg.author,
+                                                        sender.as_ref(),
+                                                        &msg.text
+                                                    ).ok()
+                                                } else {
+                                                    get_identity(&db).ok()
+                                                        .and_then(|rsa| ::encrypter::decrypt(&msg.text, &rsa, sender.as_ref()).ok())
+                                                };
+
+                                                if decrypted.is_none() && !::ratchet::is_ratchet(&msg.text) {
+                                                    // Maybe it was sent with a key exchanged by hand
+                                                    let mut stmt = db.prepare_cached("SELECT private FROM pms WHERE recipient = ?")
+                                                        .unwrap();
+                                                    let mut rows = stmt.query(&[&(msg.author as i64)]).unwrap();
+
+                                                    if let Some(row) = rows.next() {
+                                                        let row = row.unwrap();
+
+                                                        let private = vault::open(&db, &row.get::<_, Vec<u8>>(0))
+                                                            .and_then(|pem| Ok(Rsa::private_key_from_pem(&pem)?));
+                                                        match private {
+                                                            Ok(rsa) => decrypted = ::encrypter::decrypt(&msg.text, &rsa, sender.as_ref()).ok(),
+                                                            Err(err) => {
+                                                                println!("Failed to deserialize PEM.");
+                                                                println!("Did you edit the SQLite database?");
+                                                                println!("Details: {}", err);
+                                                            }
+                                                        }
+                                                    }
+                                                }
+                                                decrypted
+                                            };
+
+                                            let user = session.users.get(&msg.author)
+                                                .map(|user| &*user.name)
+                                                .unwrap_or("unknown");
+                                            if redelivered {
+                                                // Already in the history, the acknowledgement just never arrived
+                                            } else if let Some(ref decrypted) = decrypted {
+                                                let mut note = if msg.id.is_some() {
+                                                    format!(" ({}, while you were offline)", to_relative_time(msg.timestamp))
+                                                } else {
+                                                    String::new()
+                                                };
+                                                if ::encrypter::is_legacy(&msg.text) {
+                                                    note.push_str(" [unverified]");
+                                                }
+                                                println!(
+                                                    "{} privately messaged you{}: {}",
+                                                    user,
+                                                    note,
+                                                    String::from_utf8_lossy(decrypted)
+                                                );
+                                                let stored = store_pm(
+                                                    &db.lock().unwrap(),
+                                                    &session.addr.to_string(),
+                                                    msg.author,
+                                                    msg.author,
+                                                    decrypted,
+                                                    msg.timestamp,
+                                                    msg.id
+                                                );
+                                                if let Err(err) = stored {
+                                                    println!("Failed to save the message to your history");
+                                                    println!("{}", err);
+                                                }
+                                                if session.query != Some(msg.author) {
+                                                    *session.pm_unread.entry(msg.author).or_insert(0) += 1;
+                                                    screen.update(session);
                                                 }
-                                                if msg.author == session.id {
-                                                    session.last = Some((msg.id, msg.text));
+                                            } else {
+                                                println!("{} sent you a private message that couldn't be decrypted or verified", user);
+                                            }
+                                            if let Some(id) = msg.id {
+                                                let packet = Packet::PMAck(common::PMAck {
+                                                    id: id
+                                                });
+                                                if let Err(err) = common::write(&mut session.stream, &packet) {
+                                                    println!("Failed to acknowledge private message: {}", err);
                                                 }
                                             }
                                         },
-                                        Packet::PMReceive(msg) => {
+                                        Packet::PrekeyReceive(event) => {
+                                            let pending = session.pending_pms.remove(&event.user).unwrap_or_default();
+                                            let server = session.addr.to_string();
                                             let db = db.lock().unwrap();
-                                            let mut stmt = db.prepare_cached("SELECT private FROM pms WHERE recipient = ?")
-                                                .unwrap();
-                                            let mut rows = stmt.query(&[&(msg.author as i64)]).unwrap();
-
-                                            if let Some(row) = rows.next() {
-                                                let row = row.unwrap();
-
-                                                use openssl::rsa::Rsa;
-                                                match Rsa::private_key_from_pem(&row.get::<_, Vec<u8>>(0)) {
-                                                    Ok(rsa) => {
-                                                        if let Ok(decrypted) = ::encrypter::decrypt(&msg.text, &rsa) {
-                                                            let user = session.users.get(&msg.author)
-                                                                .map(|user| &*user.name)
-                                                                .unwrap_or("unknown");
-                                                            println!(
-                                                                "{} privately messaged you: {}",
-                                                                user,
-                                                                String::from_utf8_lossy(&decrypted)
-                                                            );
+
+                                            let name = session.users.get(&event.user)
+                                                .map(|user| user.name.clone())
+                                                .unwrap_or_else(|| String::from("unknown"));
+
+                                            let ready = if let Some(ref prekeys) = event.inner {
+                                                use openssl::pkey::PKey;
+                                                let started = get_user_key(session, event.user)
+                                                    .ok_or_else(|| String::from("They haven't published an identity key"))
+                                                    .and_then(|peer_key| {
+                                                        get_identity(&db).and_then(|rsa| Ok(PKey::from_rsa(rsa)?))
+                                                            .map_err(|err| err.to_string())
+                                                            .and_then(|identity| ::ratchet::start(
+                                                                &db,
+                                                                &server,
+                                                                event.user,
+                                                                prekeys,
+                                                                event.one_time.as_ref().map(|key| &**key),
+                                                                &peer_key,
+                                                                &identity
+                                                            ).map_err(|err| err.to_string()))
+                                                    });
+                                                if let Err(ref err) = started {
+                                                    println!("Failed to set up an encrypted session with {}: {}", name, err);
+                                                }
+                                                started.is_ok()
+                                            } else {
+                                                true
+                                            };
+
+                                            if ready {
+                                                for text in pending {
+                                                    let encrypted = if event.inner.is_some() {
+                                                        ::ratchet::encrypt(&db, &server, event.user, text.as_bytes())
+                                                            .map_err(|err| err.to_string())
+                                                    } else {
+                                                        match session.users.get(&event.user) {
+                                                            Some(user) => encrypt_pm(&db, user, text.as_bytes()),
+                                                            None => Err(String::from("No such user"))
+                                                        }
+                                                    };
+                                                    let packet = match encrypted {
+                                                        Ok(encrypted) => Packet::PrivateMessage(common::PrivateMessage {
+                                                            text: encrypted,
+                                                            recipient: event.user
+                                                        }),
+                                                        Err(err) => {
+                                                            println!("Failed to encrypt private message to {}: {}", name, err);
+                                                            continue;
+                                                        }
+                                                    };
+                                                    if let Err(err) = common::write(&mut session.stream, &packet) {
+                                                        println!("Failed to send private message: {}", err);
+                                                    } else {
+                                                        println!("You privately messaged {}: {}", name, text);
+                                                        if let Err(err) = store_pm(&db, &server, event.user, session.id, text.as_bytes(), get_timestamp(), None) {
+                                                            println!("Failed to save the message to your history");
+                                                            println!("{}", err);
                                                         }
-                                                    },
-                                                    Err(err) => {
-                                                        println!("Failed to deserialize PEM.");
-                                                        println!("Did you edit the SQLite database?");
-                                                        println!("Details: {}", err);
                                                     }
                                                 }
                                             }
-                                        }
-                                        Packet::RateLimited(time) => {
-                                            println!("Slow down! You may try again in {} seconds.", time);
+                                        },
+                                        Packet::PrekeyStatusReceive(event) => {
+                                            let db = db.lock().unwrap();
+                                            if event.published {
+                                                ::ratchet::prune(&db, &session.addr.to_string());
+                                            } else {
+                                                let addr = session.addr;
+                                                ::connect::publish_prekeys(&db, &addr, &screen, &mut session.stream);
+                                            }
+                                        },
+                                        Packet::PresenceReceive(event) => {
+                                            session.presence.insert(event.id, event);
+                                        },
+                                        Packet::ReactionDeleteReceive(event) => {
+                                            if session.channel == Some(event.channel) {
+                                                let user = session.users.get(&event.author)
+                                                    .map(|user| &*user.name)
+                                                    .unwrap_or("unknown");
+                                                println!(
+                                                    "{} removed their reaction {} from message #{}",
+                                                    user,
+                                                    frontend::sanitize(event.emoji),
+                                                    event.message
+                                                );
+                                            }
+                                        },
+                                        Packet::ReactionReceive(event) => {
+                                            if session.channel == Some(event.channel) {
+                                                let user = session.users.get(&event.author)
+                                                    .map(|user| &*user.name)
+                                                    .unwrap_or("unknown");
+                                                println!(
+                                                    "{} reacted to message #{} with {}",
+                                                    user,
+                                                    event.message,
+                                                    frontend::sanitize(event.emoji)
+                                                );
+                                            }
+                                        },
+                                        Packet::RateLimited(time, channel) => {
+                                            if let Some(channel) = channel.and_then(|id| session.channels.get(&id)) {
+                                                println!(
+                                                    "#{} is in slow mode. You may send another message in {} seconds.",
+                                                    channel.name,
+                                                    time
+                                                );
+                                            } else {
+                                                println!("Slow down! You may try again in {} seconds.", time);
+                                            }
+                                        },
+                                        Packet::SearchReceive(event) => {
+                                            let mut msg = event.inner;
+                                            decrypt_message(&db.lock().unwrap(), session, &mut msg);
+                                            let user = session.users.get(&msg.author)
+                                                .map(|user| &*user.name)
+                                                .unwrap_or("unknown");
+                                            let channel = session.channels.get(&msg.channel)
+                                                .map(|channel| &*channel.name)
+                                                .unwrap_or("unknown");
+                                            println!(
+                                                "Found in #{}: {} (ID #{}): {}",
+                                                channel,
+                                                user,
+                                                msg.id,
+                                                frontend::sanitize(
+                                                    String::from_utf8_lossy(&msg.text)
+                                                        .into_owned()
+                                                )
+                                            );
+                                        },
+                                        Packet::TokenDeleteReceive(event) => {
+                                            println!("Revoked session #{}", event.id);
+                                        },
+                                        Packet::TokenReceive(event) => {
+                                            let token = event.inner;
+                                            println!(
+                                                "Session #{}: {}{} - last used {} from {}, created {}",
+                                                token.id,
+                                                frontend::sanitize(token.label),
+                                                if token.current { " (this device)" } else { "" },
+                                                to_relative_time(token.timestamp_used),
+                                                token.last_ip,
+                                                to_relative_time(token.timestamp)
+                                            );
                                         },
                                         Packet::TypingReceive(event) => {
                                             if event.author != session.id {
                                                 session.typing.insert((event.author, event.channel), Instant::now());
                                             }
                                         },
+                                        Packet::UnreadReceive(event) => {
+                                            session.unread.insert(event.channel, event);
+                                            screen.update(session);
+                                        },
+                                        Packet::UserDeleteReceive(event) => {
+                                            if event.id == session.id {
+                                                println!("Your account was deleted. Goodbye!");
+                                            }
+                                            session.presence.remove(&event.id);
+                                            session.users.remove(&event.id);
+                                        },
                                         Packet::UserReceive(event) => {
+                                            if let Some(ref public_key) = event.inner.public_key {
+                                                let fingerprint = ::encrypter::fingerprint(public_key);
+                                                let server = session.addr.to_string();
+                                                let db = db.lock().unwrap();
+
+                                                let known: Option<String> = db.query_row(
+                                                    "SELECT fingerprint FROM keys WHERE server = ? AND user = ?",
+                                                    &[&server, &(event.inner.id as i64)],
+                                                    |row| row.get(0)
+                                                ).ok();
+                                                if known.as_ref() != Some(&fingerprint) {
+                                                    // Your own key changes whenever you log in from another device
+                                                    if known.is_some() && event.inner.id != session.id {
+                                                        println!("WARNING: The identity key of {} has changed!", event.inner.name);
+                                                        println!("New fingerprint: {}", fingerprint);
+                                                        println!("Unless they told you so, someone may be intercepting your private messages.");
+                                                        println!("Compare the fingerprint with them through some other way.");
+                                                    }
+                                                    db.execute(
+                                                        "REPLACE INTO keys (fingerprint, server, user) VALUES (?, ?, ?)",
+                                                        &[&fingerprint, &server, &(event.inner.id as i64)]
+                                                    ).unwrap();
+                                                }
+                                            }
                                             session.users.insert(event.inner.id, event.inner);
                                         },
                                         Packet::Err(common::ERR_GROUP_INVALID_POS) => {
@@ -213,6 +685,41 @@ pub fn listen(
                                         Packet::Err(common::ERR_UNKNOWN_GROUP) => {
                                             println!("This group was deleted");
                                         },
+                                        Packet::Err(common::ERR_UNKNOWN_MESSAGE) => {
+                                            println!("No message with that ID exists");
+                                        },
+                                        Packet::Err(common::ERR_UNKNOWN_ATTACHMENT) => {
+                                            println!("No attachment with that ID exists");
+                                            // Whatever was being downloaded won't arrive now
+                                            session.downloads.clear();
+                                        },
+                                        Packet::Err(common::ERR_ATTACHMENT_INVALID_CHUNK) => {
+                                            if session.export.is_some() {
+                                                println!("Export failed. Please try again");
+                                                session.export = None;
+                                            } else if session.downloads.is_empty() {
+                                                println!("Upload failed. Please try again");
+                                            } else {
+                                                println!("Download failed. Please try again");
+                                                session.downloads.clear();
+                                            }
+                                        },
+                                        Packet::Err(common::ERR_INVALID_REACTION) => {
+                                            println!("That's not a valid reaction. Use a single emoji");
+                                        },
+                                        Packet::Err(common::ERR_UNKNOWN_TOKEN) => {
+                                            println!("No session with that ID exists");
+                                        },
+                                        Packet::Err(common::ERR_LOGIN_LOCKED) => {
+                                            println!("Too many failed attempts. Try again later");
+                                        },
+                                        Packet::Err(common::ERR_PASSWORD_WEAK) => {
+                                            println!("That password is too weak. Try a longer one with more kinds of characters");
+                                        },
+                                        Packet::Err(common::ERR_KEY_MISMATCH) => {
+                                            println!("Another device already published different keys, so private messages go there");
+                                            println!("Use /rotatekey to receive them on this device instead");
+                                        },
                                         packet => {
                                             println!("Unimplemented packet: {:?}", packet);
                                         }
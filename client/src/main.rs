@@ -14,21 +14,29 @@ use rusqlite::Connection as SqlConnection;
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::mem;
 use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[cfg(feature = "termion")]
 mod frontend_minimal;
 #[cfg(feature = "cursive")]
 mod frontend_cursive;
+mod channel_encrypter;
 mod connect;
 mod encrypter;
+mod error;
 mod help;
 mod listener;
 mod parser;
+mod ratchet;
+mod vault;
 
 #[cfg(feature = "termion")]
 use frontend_minimal as frontend;
@@ -44,26 +52,48 @@ pub enum LogEntryId {
 
 pub struct Session {
     addr: SocketAddr,
+    attachments: Vec<usize>,
     channel: Option<usize>,
     channels: HashMap<usize, common::Channel>,
+    downloads: HashMap<usize, File>,
+    export: Option<File>,
     groups: HashMap<usize, common::Group>,
     id: usize,
     last: Option<(usize, Vec<u8>)>,
+    members: HashMap<usize, Vec<usize>>,
+    // Channel ID -> new key waiting for the server, and the messages encrypted with it
+    pending_keys: HashMap<usize, (channel_encrypter::Rotation, Vec<Packet>)>,
+    pending_pms: HashMap<usize, Vec<String>>,
+    // Everyone with a private conversation, and how many of their messages are unread
+    pm_unread: HashMap<usize, usize>,
+    presence: HashMap<usize, common::PresenceReceive>,
+    query: Option<usize>,
     stream: SslStream<TcpStream>,
     typing: HashMap<(usize, usize), Instant>,
+    unread: HashMap<usize, common::UnreadReceive>,
     users: HashMap<usize, common::User>
 }
 impl Session {
     pub fn new(addr: SocketAddr, id: usize, stream: SslStream<TcpStream>) -> Session {
         Session {
             addr: addr,
+            attachments: Vec::new(),
             channel: None,
             channels: HashMap::new(),
+            downloads: HashMap::new(),
+            export: None,
             groups: HashMap::new(),
             id: id,
             last: None,
+            members: HashMap::new(),
+            pending_keys: HashMap::new(),
+            pending_pms: HashMap::new(),
+            pm_unread: HashMap::new(),
+            presence: HashMap::new(),
+            query: None,
             stream: stream,
             typing: HashMap::new(),
+            unread: HashMap::new(),
             users: HashMap::new()
         }
     }
@@ -104,23 +134,81 @@ fn main() {
             return;
         }
     };
+    db.execute("CREATE TABLE IF NOT EXISTS channel_keys (
+                    author      INTEGER NOT NULL,
+                    channel     INTEGER NOT NULL,
+                    id          BLOB NOT NULL,
+                    key         BLOB NOT NULL,
+                    members     TEXT NOT NULL,
+                    server      TEXT NOT NULL,
+                    PRIMARY KEY (server, author, id)
+                )", &[])
+        .expect("Couldn't create SQLite table");
     db.execute("CREATE TABLE IF NOT EXISTS data (
                     key     TEXT NOT NULL UNIQUE,
                     value   TEXT NOT NULL
                 )", &[])
         .expect("Couldn't create SQLite table");
+    db.execute("CREATE TABLE IF NOT EXISTS keys (
+                    fingerprint TEXT NOT NULL,
+                    server      TEXT NOT NULL,
+                    user        INTEGER NOT NULL,
+                    PRIMARY KEY (server, user)
+                )", &[])
+        .expect("Couldn't create SQLite table");
+    db.execute("CREATE TABLE IF NOT EXISTS pm_history (
+                    author      INTEGER NOT NULL,
+                    peer        INTEGER NOT NULL,
+                    queue_id    INTEGER,
+                    server      TEXT NOT NULL,
+                    text        BLOB NOT NULL,
+                    timestamp   INTEGER NOT NULL
+                )", &[])
+        .expect("Couldn't create SQLite table");
+    // Messages queued while offline are redelivered until they're acknowledged, so only keep them once
+    db.execute("CREATE UNIQUE INDEX IF NOT EXISTS pm_history_queue_id ON pm_history (server, queue_id)", &[])
+        .expect("Couldn't create SQLite index");
     db.execute("CREATE TABLE IF NOT EXISTS pms (
                     private     BLOB NOT NULL,
                     public      BLOB NOT NULL,
                     recipient   INTEGER NOT NULL PRIMARY KEY
                 )", &[])
         .expect("Couldn't create SQLite table");
+    db.execute("CREATE TABLE IF NOT EXISTS prekeys (
+                    private     BLOB NOT NULL,
+                    public      BLOB NOT NULL,
+                    server      TEXT NOT NULL,
+                    signed      INTEGER NOT NULL
+                )", &[])
+        .expect("Couldn't create SQLite table");
+    db.execute("CREATE TABLE IF NOT EXISTS ratchets (
+                    peer        INTEGER NOT NULL,
+                    server      TEXT NOT NULL,
+                    state       BLOB NOT NULL,
+                    PRIMARY KEY (server, peer)
+                )", &[])
+        .expect("Couldn't create SQLite table");
     db.execute("CREATE TABLE IF NOT EXISTS servers (
                     ip      TEXT NOT NULL PRIMARY KEY,
                     key     BLOB NOT NULL,
                     token   TEXT
                 )", &[])
         .expect("Couldn't create SQLite table");
+    // These used to be text, but encrypted they aren't
+    db.execute("UPDATE data SET value = CAST(value AS BLOB)
+                WHERE key IN ('identity', 'ratchet_identity') AND typeof(value) = 'text'", &[])
+        .expect("Couldn't migrate SQLite table");
+    db.execute("UPDATE servers SET token = CAST(token AS BLOB) WHERE typeof(token) = 'text'", &[])
+        .expect("Couldn't migrate SQLite table");
+    // The unlocked key is stored in a temporary table, so don't put that on disk
+    db.execute_batch("PRAGMA temp_store = MEMORY").expect("Couldn't configure SQLite");
+
+    if vault::is_enabled(&db) {
+        println!("Enter your passphrase to unlock your data: ");
+        if !unlock(&db, &screen) {
+            return;
+        }
+    }
 
     let mut nick = {
         let mut stmt = db.prepare("SELECT value FROM data WHERE key = 'nick'").unwrap();
@@ -151,6 +239,31 @@ fn main() {
             }
         }
     }
+    macro_rules! send_pm {
+        ($session:expr, $id:expr, $name:expr, $text:expr) => {
+            let result = prepare_pm(&db.lock().unwrap(), $session, $id, $text);
+            match result {
+                Ok(Some(packet)) => {
+                    let mut sent = if let Packet::PrivateMessage(_) = packet { true } else { false };
+                    write!($session, packet, { sent = false; });
+                    // Only kept in the history once it's actually sent
+                    if sent {
+                        println!("You privately messaged {}: {}", $name, $text);
+                        let server = $session.addr.to_string();
+                        if let Err(err) = store_pm(&db.lock().unwrap(), &server, $id, $session.id, $text.as_bytes(), get_timestamp(), None) {
+                            println!("Failed to save the message to your history");
+                            println!("{}", err);
+                        }
+                    }
+                },
+                Ok(None) => (),
+                Err(err) => {
+                    println!("Error! Failed to encrypt! D:");
+                    println!("{}", err);
+                }
+            }
+        }
+    }
 
     println!("Welcome, {}", nick);
     println!("To quit, type /quit");
@@ -244,6 +357,49 @@ fn main() {
             }
 
             match &*command {
+                "account" => {
+                    usage_min!(1, "account <delete/export <path>>");
+                    match &*args[0] {
+                        "delete" => {
+                            usage!(1, "account delete");
+                            {
+                                let mut session = session.lock().unwrap();
+                                let session = require_session!(session);
+
+                                println!("This will permanently delete your account on this server.");
+                                println!("Enter your password to confirm: ");
+                                let password = readpass!({ continue; });
+
+                                let packet = Packet::AccountDelete(common::AccountDelete {
+                                    password: password
+                                });
+                                write!(session, packet, { continue; })
+                            }
+                            let _ = rx_sent.recv_timeout(Duration::from_secs(10));
+                        },
+                        "export" => {
+                            usage!(2, "account export <path>");
+                            let mut session = session.lock().unwrap();
+                            let session = require_session!(session);
+
+                            let file = match File::create(&args[1]) {
+                                Ok(ok) => ok,
+                                Err(err) => {
+                                    println!("Failed to create file");
+                                    println!("{}", err);
+                                    continue;
+                                }
+                            };
+                            session.export = Some(file);
+
+                            let packet = Packet::AccountExport(common::AccountExport {
+                                offset: 0
+                            });
+                            write!(session, packet, {})
+                        },
+                        _ => println!("Usage: /account <delete/export <path>>")
+                    }
+                },
                 "ban" | "unban" => {
                     usage!(1, "ban/unban <user>");
                     let mut session = session.lock().unwrap();
@@ -264,7 +420,8 @@ fn main() {
                     write!(session, packet, {})
                 },
                 "connect" => {
-                    usage!(1, "connect <ip[:port]>");
+                    usage_min!(1, "connect <ip[:port]> [invite]");
+                    usage_max!(2, "connect <ip[:port]> [invite]");
                     let mut session = session.lock().unwrap();
                     if session.is_some() {
                         println!("You have to disconnect before doing that.");
@@ -278,7 +435,8 @@ fn main() {
                             continue;
                         }
                     };
-                    *session = connect::connect(addr, &db.lock().unwrap(), &nick, &screen, &ssl);
+                    let invite = args.get(1).map(|invite| &**invite);
+                    *session = connect::connect(addr, &db.lock().unwrap(), invite, &nick, &screen, &ssl);
                 },
                 "create" => {
                     usage_min!(2, "create <\"channel\"/\"group\"> <name> [data]");
@@ -286,14 +444,25 @@ fn main() {
                     let session = require_session!(session);
                     let packet = match &*args[0] {
                         "channel" => {
-                            usage_max!(2, "create channel <name>");
+                            usage_max!(3, "create channel <name> [\"encrypted\"]");
+                            let encrypted = match args.get(2).map(|arg| &**arg) {
+                                Some("encrypted") => true,
+                                Some(_) => {
+                                    println!("Usage: /create channel <name> [\"encrypted\"]");
+                                    continue;
+                                },
+                                None => false
+                            };
                             let mut name = args.remove(1);
                             if name.starts_with('#') {
                                 name.drain(..1);
                             }
                             Packet::ChannelCreate(common::ChannelCreate {
+                                category: None,
+                                encrypted: encrypted,
                                 overrides: HashMap::new(),
-                                name: name
+                                name: name,
+                                pos: session.channels.len() + 1
                             })
                         },
                         "group" => {
@@ -364,6 +533,66 @@ fn main() {
                     }
                     *session = None;
                 },
+                "download" => {
+                    usage!(2, "download <id> <path>");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+                    let id = match args[0].parse() {
+                        Ok(ok) => ok,
+                        Err(_) => {
+                            println!("Failed to parse ID");
+                            continue;
+                        }
+                    };
+                    let file = match File::create(&args[1]) {
+                        Ok(ok) => ok,
+                        Err(err) => {
+                            println!("Failed to create file");
+                            println!("{}", err);
+                            continue;
+                        }
+                    };
+                    session.downloads.insert(id, file);
+
+                    let packet = Packet::AttachmentDownload(common::AttachmentDownload {
+                        id: id,
+                        offset: 0
+                    });
+                    write!(session, packet, {})
+                },
+                "fingerprint" => {
+                    usage_max!(1, "fingerprint [user]");
+
+                    let public_key = if args.is_empty() {
+                        match get_identity(&db.lock().unwrap()).and_then(|rsa| Ok(rsa.public_key_to_pem()?)) {
+                            Ok(ok) => ok,
+                            Err(err) => {
+                                println!("Failed to load your identity key");
+                                println!("{}", err);
+                                continue;
+                            }
+                        }
+                    } else {
+                        let mut session = session.lock().unwrap();
+                        let session = require_session!(session);
+
+                        let user = match find_user(&session.users, &args[0]) {
+                            Some(user) => user,
+                            None => {
+                                println!("No such user");
+                                continue;
+                            }
+                        };
+                        match user.public_key {
+                            Some(ref public_key) => public_key.clone(),
+                            None => {
+                                println!("{} hasn't published a key yet", user.name);
+                                continue;
+                            }
+                        }
+                    };
+                    println!(encrypter::fingerprint(&public_key));
+                },
                 "forget" => {
                     usage!(1, "forget <ip>");
                     let addr = match parse_ip(&args[0]) {
@@ -397,9 +626,24 @@ fn main() {
                             || Ok(channel.id) == id {
                             println!("Channel #{}", channel.name);
                             println!("ID: #{}", channel.id);
+                            println!("Position: {}", channel.pos);
+                            if let Some(ref category) = channel.category {
+                                println!("Category: {}", frontend::sanitize(category.clone()));
+                            }
+                            if let Some(ref topic) = channel.topic {
+                                println!("Topic: {}", frontend::sanitize(topic.clone()));
+                            }
                             for (id, &(allow, deny)) in &channel.overrides {
                                 println!("Permission override: Role #{} = {}", id, to_perm_string(allow, deny));
                             }
+                            println!("Max message age: {}", to_optional_string(channel.retention_max_age));
+                            println!("Max message count: {}", to_optional_string(channel.retention_max_count));
+                            if channel.slow_mode != 0 {
+                                println!("Slow mode: {} seconds", channel.slow_mode);
+                            }
+                            if channel.encrypted {
+                                println!("End-to-end encrypted");
+                            }
                         }
                     }
                     for group in session.groups.values() {
@@ -427,11 +671,68 @@ fn main() {
                             if user.ban {
                                 println!("Banned.");
                             }
+                            if user.owner {
+                                println!("Owner of this server.");
+                            }
+                            println!("Presence:{}", to_presence_string(session.presence.get(&user.id)));
+                            let profile = &user.profile;
+                            if let Some(ref display_name) = profile.display_name {
+                                println!("Display name: {}", frontend::sanitize(display_name.clone()));
+                            }
+                            if let Some(ref pronouns) = profile.pronouns {
+                                println!("Pronouns: {}", frontend::sanitize(pronouns.clone()));
+                            }
+                            if let Some(ref timezone) = profile.timezone {
+                                println!("Timezone: {}", frontend::sanitize(timezone.clone()));
+                            }
+                            if let Some(ref bio) = profile.bio {
+                                println!("Bio: {}", frontend::sanitize(bio.clone()));
+                            }
                             println!("Bot: {}", if user.bot { "true" } else { "false" });
                             println!("ID: #{}", user.id);
                         }
                     }
                 },
+                "invite" => {
+                    usage_min!(1, "invite <create [max uses] [max age]/list>");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+
+                    let packet = match &*args[0] {
+                        "create" => {
+                            usage_max!(3, "invite create [max uses] [max age]");
+                            let max_uses = match args.get(1).map(|uses| uses.parse()) {
+                                None => None,
+                                Some(Ok(uses)) => Some(uses),
+                                Some(Err(_)) => {
+                                    println!("Failed to parse max uses");
+                                    continue;
+                                }
+                            };
+                            let max_age = match args.get(2).map(|age| age.parse()) {
+                                None => None,
+                                Some(Ok(age)) => Some(age),
+                                Some(Err(_)) => {
+                                    println!("Failed to parse max age");
+                                    continue;
+                                }
+                            };
+                            Packet::InviteCreate(common::InviteCreate {
+                                max_age: max_age,
+                                max_uses: max_uses
+                            })
+                        },
+                        "list" => {
+                            usage!(1, "invite list");
+                            Packet::InviteList(common::InviteList {})
+                        },
+                        _ => {
+                            println!("Usage: /invite <create [max uses] [max age]/list>");
+                            continue;
+                        }
+                    };
+                    write!(session, packet, {})
+                },
                 "join" => {
                     usage!(1, "join <channel>");
                     let mut session = session.lock().unwrap();
@@ -445,8 +746,12 @@ fn main() {
                     for channel in session.channels.values() {
                         if channel.name == name {
                             session.channel = Some(channel.id);
+                            session.query = None;
                             screen.clear();
                             println!("Joined channel #{}", channel.name);
+                            if let Some(ref topic) = channel.topic {
+                                println!("Topic: {}", frontend::sanitize(topic.clone()));
+                            }
                             packet = Some(Packet::MessageList(common::MessageList {
                                 after: None,
                                 before: None,
@@ -457,6 +762,7 @@ fn main() {
                         }
                     }
                     if let Some(packet) = packet {
+                        screen.update(session);
                         write!(session, packet, {});
                     } else {
                         println!("No channel found with that name");
@@ -470,16 +776,19 @@ fn main() {
                         "channels" => {
                             // Yeah, cloning this is bad... But what can I do? I need to sort!
                             // Though, I suspect this might be a shallow copy.
-                            let mut channels: Vec<_> = session.channels.values().collect();
-                            channels.sort_by_key(|item| &item.name);
-
-                            let result = channels.iter().fold(String::new(), |mut acc, channel| {
-                                if !acc.is_empty() { acc.push_str(", "); }
-                                acc.push('#');
-                                acc.push_str(&channel.name);
-                                acc
-                            });
-                            println!(result);
+                            for (category, channels) in get_channel_categories(&session.channels) {
+                                let result = channels.iter().fold(String::new(), |mut acc, channel| {
+                                    if !acc.is_empty() { acc.push_str(", "); }
+                                    acc.push('#');
+                                    acc.push_str(&channel.name);
+                                    acc.push_str(&to_unread_string(session.unread.get(&channel.id)));
+                                    acc
+                                });
+                                match category {
+                                    Some(category) => println!("{}: {}", frontend::sanitize(category.to_string()), result),
+                                    None => println!(result)
+                                }
+                            }
                         },
                         "groups" => {
                             // Read the above comment, thank you ---------------------------^
@@ -506,6 +815,10 @@ fn main() {
                                     if user.ban == *banned {
                                         if !acc.is_empty() { acc.push_str(", "); }
                                         acc.push_str(&user.name);
+                                        if user.owner {
+                                            acc.push_str(" (owner)");
+                                        }
+                                        acc.push_str(&to_presence_string(session.presence.get(&user.id)));
                                     }
                                     acc
                                 }));
@@ -514,60 +827,84 @@ fn main() {
                         _ => println!("Unable to list that")
                     }
                 },
-                "msg" => {
-                    usage!(2, "msg <user> <message>");
+                "lock" => {
+                    usage!(0, "lock");
+                    if !vault::is_enabled(&db.lock().unwrap()) {
+                        println!("You haven't set a passphrase. See /passphrase");
+                        continue;
+                    }
+                    // Nothing can be sent or received while locked
+                    let addr = {
+                        let mut session = session.lock().unwrap();
+                        let addr = session.as_ref().map(|session| session.addr);
+                        if let Some(ref mut session) = *session {
+                            let _ = common::write(&mut session.stream, &Packet::Close);
+                        }
+                        *session = None;
+                        addr
+                    };
+                    vault::lock(&db.lock().unwrap());
+
+                    screen.clear();
+                    println!("Locked. Enter your passphrase to unlock: ");
+                    if !unlock(&db.lock().unwrap(), &screen) {
+                        break;
+                    }
+                    println!("Unlocked");
+                    if let Some(addr) = addr {
+                        *session.lock().unwrap() = connect::connect(addr, &db.lock().unwrap(), None, &nick, &screen, &ssl);
+                    }
+                },
+                "move" => {
+                    usage!(2, "move <channel> <position>");
                     let mut session = session.lock().unwrap();
                     let session = require_session!(session);
 
-                    let packet = {
-                        let user = match find_user(&session.users, &args[0]) {
-                            Some(user) => user,
-                            None => {
-                                println!("No such user");
-                                continue;
-                            }
-                        };
+                    let mut name = &*args[0];
+                    if name.starts_with('#') {
+                        name = &name[1..];
+                    }
+                    let pos: usize = match args[1].parse() {
+                        Ok(ok) if ok > 0 => ok,
+                        _ => {
+                            println!("Not a valid position");
+                            continue;
+                        }
+                    };
+
+                    let mut ids: Vec<_> = get_channel_categories(&session.channels).into_iter()
+                        .flat_map(|(_, channels)| channels)
+                        .map(|channel| channel.id)
+                        .collect();
+                    let id = match session.channels.values().find(|channel| channel.name == name) {
+                        Some(channel) => channel.id,
+                        None => {
+                            println!("No channel found with that name");
+                            continue;
+                        }
+                    };
+                    ids.retain(|other| *other != id);
+                    let pos = (pos - 1).min(ids.len());
+                    ids.insert(pos, id);
 
-                        let public = {
-                            let db = db.lock().unwrap();
-                            let mut stmt = db.prepare_cached("SELECT public FROM pms WHERE recipient = ?").unwrap();
-                            let mut rows = stmt.query(&[&(user.id as i64)]).unwrap();
+                    let packet = Packet::ChannelReorder(common::ChannelReorder {
+                        ids: ids
+                    });
+                    write!(session, packet, {})
+                },
+                "msg" => {
+                    usage!(2, "msg <user> <message>");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
 
-                            if let Some(row) = rows.next() {
-                                let row = row.unwrap();
-                                row.get::<_, String>(0)
-                            } else {
-                                println!("Please run `/setupkeys {}` first", user.name);
-                                continue;
-                            }
-                        };
-                        use openssl::rsa::Rsa;
-                        let rsa = match Rsa::public_key_from_pem(public.as_bytes()) {
-                            Ok(ok) => ok,
-                            Err(err) => {
-                                println!("Error! Is that valid PEM data?");
-                                println!("Details: {}", err);
-                                continue;
-                            }
-                        };
-                        Packet::PrivateMessage(common::PrivateMessage {
-                            text: match encrypter::encrypt(args[1].as_bytes(), &rsa) {
-                                Ok(ok) => ok,
-                                Err(err) => {
-                                    println!("Error! Failed to encrypt! D:");
-                                    println!("{}", err);
-                                    continue;
-                                }
-                            },
-                            recipient: user.id
-                        })
+                    let id = match find_user(&session.users, &args[0]) {
+                        Some(user) => user.id,
+                        None => {
+                            println!("No such user");
+                            continue;
+                        }
                     };
-                    write!(session, packet, {});
-                    println!(
-                        "You privately messaged {}: {}",
-                        args[0],
-                        args[1]
-                    );
+                    send_pm!(session, id, args[0], &args[1]);
                 },
                 "nick" => {
                     usage!(1, "nick <name>");
@@ -592,6 +929,75 @@ fn main() {
                     println!("Your name is now {}", new);
                     nick = Cow::from(new);
                 },
+                "owner" => {
+                    usage!(2, "owner <add/transfer> <user>");
+                    let keep = match &*args[0] {
+                        "add" => true,
+                        "transfer" => false,
+                        _ => {
+                            println!("Usage: /owner <add/transfer> <user>");
+                            continue;
+                        }
+                    };
+                    {
+                        let mut session = session.lock().unwrap();
+                        let session = require_session!(session);
+
+                        let id = match find_user(&session.users, &args[1]) {
+                            Some(user) => user.id,
+                            None => {
+                                println!("No such user");
+                                continue;
+                            }
+                        };
+
+                        if keep {
+                            println!("{} will be able to do anything on this server, including removing you.", args[1]);
+                        } else {
+                            println!("You will no longer own this server. {} will.", args[1]);
+                        }
+                        println!("Enter your password to confirm: ");
+                        let password = readpass!({ continue; });
+
+                        let packet = Packet::OwnershipTransfer(common::OwnershipTransfer {
+                            id: id,
+                            keep: keep,
+                            password: password
+                        });
+                        write!(session, packet, { continue; })
+                    }
+                    let _ = rx_sent.recv_timeout(Duration::from_secs(10));
+                },
+                "passphrase" => {
+                    usage!(0, "passphrase");
+                    if vault::is_enabled(&db.lock().unwrap()) {
+                        println!("Current passphrase: ");
+                        let current = readpass!({ continue; });
+                        if !vault::unlock(&db.lock().unwrap(), &current).unwrap_or(false) {
+                            println!("Wrong passphrase");
+                            continue;
+                        }
+                    }
+                    println!("New passphrase (leave empty to store your data unencrypted): ");
+                    let new = readpass!({ continue; });
+                    if !new.is_empty() {
+                        println!("Repeat the new passphrase: ");
+                        if readpass!({ continue; }) != new {
+                            println!("The passphrases don't match");
+                            continue;
+                        }
+                    }
+
+                    let passphrase = if new.is_empty() { None } else { Some(&*new) };
+                    if let Err(err) = vault::set_passphrase(&mut db.lock().unwrap(), passphrase) {
+                        println!("Failed to change your passphrase");
+                        println!("{}", err);
+                    } else if passphrase.is_some() {
+                        println!("Your data is now encrypted. You'll be asked for the passphrase on startup and after /lock");
+                    } else {
+                        println!("Your data is no longer encrypted");
+                    }
+                },
                 "passwd" => {
                     usage!(0, "passwd");
                     {
@@ -613,7 +1019,233 @@ fn main() {
                     }
                     let _ = rx_sent.recv_timeout(Duration::from_secs(10));
                 },
+                "pin" | "unpin" => {
+                    usage!(1, "pin/unpin <id>");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+                    let id = match args[0].parse() {
+                        Ok(ok) => ok,
+                        Err(_) => {
+                            println!("Failed to parse ID");
+                            continue;
+                        }
+                    };
+
+                    let packet = if command == "pin" {
+                        Packet::MessagePin(common::MessagePin {
+                            id: id
+                        })
+                    } else {
+                        Packet::MessageUnpin(common::MessageUnpin {
+                            id: id
+                        })
+                    };
+                    write!(session, packet, {})
+                },
+                "pins" => {
+                    usage!(0, "pins");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+                    let channel = match session.channel {
+                        Some(some) => some,
+                        None => {
+                            println!("No channel specified. See /join");
+                            continue;
+                        }
+                    };
+
+                    let packet = Packet::PinList(common::PinList {
+                        channel: channel
+                    });
+                    write!(session, packet, {})
+                },
+                "profile" => {
+                    usage!(0, "profile");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+
+                    let profile = session.users.get(&session.id).map(|user| user.profile.clone()).unwrap_or_default();
+                    println!("Editing your profile");
+                    println!("(Press enter to keep current value, or type \"-\" to clear it)");
+                    println!();
+
+                    println!("Display name [{}]: ", profile.display_name.as_ref().map(|s| &**s).unwrap_or(""));
+                    let display_name = readline!({ continue; });
+                    println!("Pronouns [{}]: ", profile.pronouns.as_ref().map(|s| &**s).unwrap_or(""));
+                    let pronouns = readline!({ continue; });
+                    println!("Timezone [{}]: ", profile.timezone.as_ref().map(|s| &**s).unwrap_or(""));
+                    let timezone = readline!({ continue; });
+                    println!("Bio [{}]: ", profile.bio.as_ref().map(|s| &**s).unwrap_or(""));
+                    let bio = readline!({ continue; });
+
+                    let packet = Packet::ProfileUpdate(common::ProfileUpdate {
+                        inner: common::Profile {
+                            bio: parse_clearable(&bio, profile.bio),
+                            display_name: parse_clearable(&display_name, profile.display_name),
+                            pronouns: parse_clearable(&pronouns, profile.pronouns),
+                            timezone: parse_clearable(&timezone, profile.timezone)
+                        }
+                    });
+                    write!(session, packet, {})
+                },
+                "query" => {
+                    usage_max!(1, "query [user]");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+
+                    let user = match args.get(0) {
+                        Some(name) => match find_user(&session.users, name) {
+                            Some(user) => user.clone(),
+                            None => {
+                                println!("No such user");
+                                continue;
+                            }
+                        },
+                        None => {
+                            session.query = None;
+                            screen.update(session);
+                            println!("Closed private messages. See /join");
+                            continue;
+                        }
+                    };
+                    // Channel messages are counted as unread in the meantime
+                    session.channel = None;
+                    session.query = Some(user.id);
+                    session.pm_unread.insert(user.id, 0);
+
+                    screen.clear();
+                    println!("Private messages with {}", user.name);
+                    let history = get_pm_history(&db.lock().unwrap(), &session.addr.to_string(), user.id);
+                    for (author, text, timestamp) in history {
+                        let author = session.users.get(&author)
+                            .map(|user| &*user.name)
+                            .unwrap_or("unknown");
+                        println!(
+                            "[{}] {}: {}",
+                            to_relative_time(timestamp),
+                            author,
+                            frontend::sanitize(String::from_utf8_lossy(&text).into_owned())
+                        );
+                    }
+                    screen.update(session);
+                },
                 "quit" => break,
+                "react" | "unreact" => {
+                    usage!(2, "react/unreact <id> <emoji>");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+                    let id = match args[0].parse() {
+                        Ok(ok) => ok,
+                        Err(_) => {
+                            println!("Failed to parse ID");
+                            continue;
+                        }
+                    };
+                    let emoji = args.remove(1);
+
+                    let packet = if command == "react" {
+                        Packet::ReactionAdd(common::ReactionAdd {
+                            emoji: emoji,
+                            message: id
+                        })
+                    } else {
+                        Packet::ReactionRemove(common::ReactionRemove {
+                            emoji: emoji,
+                            message: id
+                        })
+                    };
+                    write!(session, packet, {})
+                },
+                "rotatekey" => {
+                    usage!(0, "rotatekey");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+
+                    let addr = session.addr;
+                    connect::publish_keys(&db.lock().unwrap(), &addr, &screen, &mut session.stream, true);
+                },
+                "search" => {
+                    usage_min!(1, "search <query> [in:channel] [from:user] [before:time] [after:time]");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+
+                    let mut search = common::MessageSearch {
+                        after: None,
+                        author: None,
+                        before: None,
+                        channel: None,
+                        limit: common::LIMIT_BULK,
+                        query: String::new()
+                    };
+                    let mut words = Vec::new();
+                    let mut valid = true;
+                    for arg in &args {
+                        if arg.starts_with("in:") {
+                            let mut name = &arg[3..];
+                            if name.starts_with('#') {
+                                name = &name[1..];
+                            }
+                            match session.channels.values().find(|channel| channel.name == name) {
+                                Some(channel) => search.channel = Some(channel.id),
+                                None => { println!("No channel found with that name"); valid = false; }
+                            }
+                        } else if arg.starts_with("from:") {
+                            match find_user(&session.users, &arg[5..]) {
+                                Some(user) => search.author = Some(user.id),
+                                None => { println!("No such user"); valid = false; }
+                            }
+                        } else if arg.starts_with("before:") {
+                            match arg[7..].parse() {
+                                Ok(ok) => search.before = Some(ok),
+                                Err(_) => { println!("Not a valid timestamp"); valid = false; }
+                            }
+                        } else if arg.starts_with("after:") {
+                            match arg[6..].parse() {
+                                Ok(ok) => search.after = Some(ok),
+                                Err(_) => { println!("Not a valid timestamp"); valid = false; }
+                            }
+                        } else {
+                            words.push(&**arg);
+                        }
+                    }
+                    if !valid {
+                        continue;
+                    }
+                    if words.is_empty() {
+                        println!("Usage: /search <query> [in:channel] [from:user] [before:time] [after:time]");
+                        continue;
+                    }
+                    search.query = words.join(" ");
+
+                    let packet = Packet::MessageSearch(search);
+                    write!(session, packet, {})
+                },
+                "sessions" => {
+                    usage_max!(2, "sessions [revoke <id>]");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+
+                    let packet = if args.is_empty() {
+                        Packet::TokenList(common::TokenList {})
+                    } else {
+                        usage!(2, "sessions [revoke <id>]");
+                        if args[0] != "revoke" {
+                            println!("Usage: /sessions [revoke <id>]");
+                            continue;
+                        }
+                        let id = match args[1].parse() {
+                            Ok(ok) => ok,
+                            Err(_) => {
+                                println!("Failed to parse ID");
+                                continue;
+                            }
+                        };
+                        Packet::TokenRevoke(common::TokenRevoke {
+                            id: id
+                        })
+                    };
+                    write!(session, packet, {})
+                },
                 "setupkeys" => {
                     usage!(1, "setupkeys <user>");
 
@@ -661,11 +1293,40 @@ fn main() {
                         println!("Details: {}", err);
                         continue;
                     }
-                    db.lock().unwrap().execute(
+                    let db = db.lock().unwrap();
+                    let private = match vault::seal(&db, &private) {
+                        Ok(ok) => ok,
+                        Err(err) => {
+                            println!("Error! Failed to encrypt the private key.");
+                            println!("Details: {}", err);
+                            continue;
+                        }
+                    };
+                    db.execute(
                         "REPLACE INTO pms (private, public, recipient) VALUES (?, ?, ?)",
                         &[&private, &key, &(id as i64)]
                     ).unwrap();
                 },
+                "status" => {
+                    usage_min!(1, "status <online/dnd> [text]");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+
+                    let dnd = match &*args[0] {
+                        "online" => false,
+                        "dnd" => true,
+                        _ => {
+                            println!("Usage: /status <online/dnd> [text]");
+                            continue;
+                        }
+                    };
+
+                    let packet = Packet::StatusUpdate(common::StatusUpdate {
+                        dnd: dnd,
+                        text: if args.len() > 1 { Some(args[1..].join(" ")) } else { None }
+                    });
+                    write!(session, packet, {})
+                },
                 "update" => {
                     usage!(2, "update <\"channel\"/\"group\"> <id>");
 
@@ -735,6 +1396,68 @@ fn main() {
                             let mut name = name.trim();
                             if name.is_empty() { name = &channel.name }
 
+                            println!("Position [{}]: ", channel.pos);
+                            let pos = readline!({ continue; });
+                            let pos = pos.trim();
+                            let pos = if pos.is_empty() {
+                                channel.pos
+                            } else {
+                                match pos.parse() {
+                                    Ok(ok) => ok,
+                                    Err(_) => {
+                                        println!("Not a valid number");
+                                        continue;
+                                    }
+                                }
+                            };
+
+                            println!("(Type \"-\" to remove it from its category)");
+                            println!("Category [{}]: ", channel.category.as_ref().map(|s| &**s).unwrap_or(""));
+                            let category = readline!({ continue; });
+                            let category = parse_clearable(&category, channel.category.clone());
+
+                            println!("(Type \"-\" to remove the topic)");
+                            println!("Topic [{}]: ", channel.topic.as_ref().map(|s| &**s).unwrap_or(""));
+                            let topic = readline!({ continue; });
+                            let topic = parse_clearable(&topic, channel.topic.clone());
+
+                            println!("(0 turns slow mode off)");
+                            println!("Slow mode in seconds [{}]: ", channel.slow_mode);
+                            let slow_mode = readline!({ continue; });
+                            let slow_mode = slow_mode.trim();
+                            let slow_mode = if slow_mode.is_empty() {
+                                channel.slow_mode
+                            } else {
+                                match slow_mode.parse() {
+                                    Ok(ok) => ok,
+                                    Err(_) => {
+                                        println!("Not a valid number");
+                                        continue;
+                                    }
+                                }
+                            };
+
+                            println!("(Type \"default\" to use the server's retention policy, or 0 to keep forever)");
+                            println!("Max message age in seconds [{}]: ", to_optional_string(channel.retention_max_age));
+                            let max_age = readline!({ continue; });
+                            let max_age = match parse_optional(&max_age, channel.retention_max_age) {
+                                Ok(ok) => ok,
+                                Err(_) => {
+                                    println!("Not a valid number");
+                                    continue;
+                                }
+                            };
+
+                            println!("Max message count [{}]: ", to_optional_string(channel.retention_max_count));
+                            let max_count = readline!({ continue; });
+                            let max_count = match parse_optional(&max_count, channel.retention_max_count) {
+                                Ok(ok) => ok,
+                                Err(_) => {
+                                    println!("Not a valid number");
+                                    continue;
+                                }
+                            };
+
                             let overrides = match screen.get_channel_overrides(channel.overrides.clone(), session) {
                                 Ok(ok) => ok,
                                 Err(_) => continue
@@ -742,9 +1465,16 @@ fn main() {
 
                             Some(Packet::ChannelUpdate(common::ChannelUpdate {
                                 inner: common::Channel {
+                                    category: category,
+                                    encrypted: channel.encrypted,
                                     id: channel.id,
                                     name: name.to_string(),
-                                    overrides: overrides
+                                    overrides: overrides,
+                                    pos: pos,
+                                    retention_max_age: max_age,
+                                    retention_max_count: max_count,
+                                    slow_mode: slow_mode,
+                                    topic: topic
                                 },
                                 keep_overrides: false
                             }))
@@ -771,6 +1501,40 @@ fn main() {
                         println!("Nothing with that ID exists");
                     }
                 },
+                "upload" => {
+                    usage!(1, "upload <path>");
+                    let mut session = session.lock().unwrap();
+                    let session = require_session!(session);
+
+                    let path = Path::new(&args[0]);
+                    let name = match path.file_name() {
+                        Some(name) => name.to_string_lossy().into_owned(),
+                        None => {
+                            println!("Not a file");
+                            continue;
+                        }
+                    };
+                    let mut data = Vec::new();
+                    if let Err(err) = File::open(path).and_then(|mut file| file.read_to_end(&mut data)) {
+                        println!("Failed to read file");
+                        println!("{}", err);
+                        continue;
+                    }
+                    if data.is_empty() {
+                        println!("Can not upload an empty file");
+                        continue;
+                    }
+
+                    for (i, chunk) in data.chunks(common::LIMIT_ATTACHMENT_CHUNK).enumerate() {
+                        let packet = Packet::AttachmentUpload(common::AttachmentUpload {
+                            data: chunk.to_vec(),
+                            name: name.clone(),
+                            offset: i * common::LIMIT_ATTACHMENT_CHUNK,
+                            size: data.len()
+                        });
+                        write!(session, packet, { break; })
+                    }
+                },
                 _ => {
                     println!("Unknown command");
                 }
@@ -804,8 +1568,13 @@ fn main() {
         let mut session = session.lock().unwrap();
         let session = require_session!(session);
 
-        if let Some(channel) = session.channel {
-            let packet = if input.starts_with("s/") && session.last.is_some() {
+        if let Some(peer) = session.query {
+            let name = session.users.get(&peer)
+                .map(|user| user.name.clone())
+                .unwrap_or_else(|| String::from("unknown"));
+            send_pm!(session, peer, name, &input);
+        } else if let Some(channel) = session.channel {
+            let mut packet = if input.starts_with("s/") && session.last.is_some() {
                 let mut parts = input[2..].splitn(2, '/');
                 let find = match parts.next() {
                     Some(some) => some,
@@ -823,11 +1592,50 @@ fn main() {
             } else {
                 screen.log_with_id(format!("{}: {}", nick, input), LogEntryId::Sending);
                 Packet::MessageCreate(common::MessageCreate {
+                    attachments: mem::replace(&mut session.attachments, Vec::new()),
                     channel: channel,
                     text: input.into_bytes()
                 })
             };
 
+            if session.channels.get(&channel).map(|channel| channel.encrypted).unwrap_or(false) {
+                let result = {
+                    let text = match packet {
+                        Packet::MessageCreate(ref msg) => &msg.text,
+                        Packet::MessageUpdate(ref msg) => &msg.text,
+                        _ => unreachable!()
+                    };
+                    match session.pending_keys.get(&channel) {
+                        // Messages queue up behind the key instead of making yet another one
+                        Some(&(ref rotation, _)) => rotation.encrypt(text).map(|encrypted| (None, encrypted)),
+                        None => encrypt_message(&db.lock().unwrap(), session, channel, text)
+                    }
+                };
+                let (rotation, encrypted) = match result {
+                    Ok(ok) => ok,
+                    Err(err) => {
+                        screen.delete(LogEntryId::Sending);
+                        println!("Error! Failed to encrypt! D:");
+                        println!("{}", err);
+                        continue;
+                    }
+                };
+                match packet {
+                    Packet::MessageCreate(ref mut msg) => msg.text = encrypted,
+                    Packet::MessageUpdate(ref mut msg) => msg.text = encrypted,
+                    _ => unreachable!()
+                }
+                if let Some(rotation) = rotation {
+                    write!(session, Packet::ChannelKeyPublish(rotation.publish.clone()), { continue; });
+                    session.pending_keys.insert(channel, (rotation, Vec::new()));
+                }
+                // Nobody could read these if the key never made it, so wait for the server to confirm it
+                if let Some(&mut (_, ref mut queue)) = session.pending_keys.get_mut(&channel) {
+                    queue.push(packet);
+                    continue;
+                }
+            }
+
             write!(session, packet, {})
         } else {
             println!("No channel specified. See /create channel, /list channels and /join");
@@ -843,10 +1651,278 @@ fn main() {
     screen.stop();
 }
 
+fn device_label() -> String {
+    format!("synac client ({})", env::consts::OS)
+}
+fn get_channel_categories(channels: &HashMap<usize, common::Channel>)
+        -> Vec<(Option<&str>, Vec<&common::Channel>)> {
+    let mut sorted: Vec<_> = channels.values().collect();
+    sorted.sort_by_key(|channel| channel.pos);
+
+    // Categories are ordered by their topmost channel
+    let mut categories: Vec<(Option<&str>, Vec<&common::Channel>)> = Vec::new();
+    for channel in sorted {
+        let category = channel.category.as_ref().map(|category| &**category);
+        if let Some(i) = categories.iter().position(|&(name, _)| name == category) {
+            categories[i].1.push(channel);
+        } else {
+            categories.push((category, vec![channel]));
+        }
+    }
+    categories
+}
+// Replaces the text of messages in encrypted channels with what it decrypts to
+fn decrypt_message(db: &SqlConnection, session: &Session, msg: &mut common::Message) {
+    if !session.channels.get(&msg.channel).map(|channel| channel.encrypted).unwrap_or(false) {
+        return;
+    }
+    msg.text = match channel_encrypter::decrypt(db, &session.addr.to_string(), msg.channel, msg.author, &msg.text) {
+        Ok(text) => text,
+        Err(err) => format!("[couldn't decrypt: {}]", err).into_bytes()
+    };
+}
+fn encrypt_message(db: &SqlConnection, session: &Session, channel: usize, text: &[u8])
+        -> Result<(Option<channel_encrypter::Rotation>, Vec<u8>), Box<std::error::Error>> {
+    let members = match session.members.get(&channel) {
+        Some(members) => members,
+        None => return Err(Box::new(error::ErrMembers))
+    };
+    channel_encrypter::encrypt(
+        db,
+        &session.addr.to_string(),
+        session.id,
+        channel,
+        members,
+        &session.users,
+        text
+    )
+}
+// The packet to send for a private message, or None if it's waiting for an earlier one
+fn prepare_pm(db: &SqlConnection, session: &mut Session, id: usize, text: &str)
+        -> Result<Option<Packet>, Box<std::error::Error>> {
+    let server = session.addr.to_string();
+    session.pm_unread.entry(id).or_insert(0);
+
+    if !ratchet::has_session(db, &server, id) {
+        // The message is sent as soon as their prekeys arrive
+        let pending = session.pending_pms.entry(id).or_insert_with(Vec::new);
+        pending.push(text.to_string());
+        return Ok(if pending.len() == 1 {
+            Some(Packet::PrekeyRequest(common::PrekeyRequest {
+                user: id
+            }))
+        } else {
+            None
+        });
+    }
+
+    let encrypted = ratchet::encrypt(db, &server, id, text.as_bytes())?;
+
+    Ok(Some(Packet::PrivateMessage(common::PrivateMessage {
+        text: encrypted,
+        recipient: id
+    })))
+}
+fn store_pm(
+    db: &SqlConnection,
+    server: &str,
+    peer: usize,
+    author: usize,
+    text: &[u8],
+    timestamp: i64,
+    queue_id: Option<usize>
+) -> Result<(), Box<std::error::Error>> {
+    let text = vault::seal(db, text)?;
+    db.execute(
+        "INSERT OR IGNORE INTO pm_history (author, peer, queue_id, server, text, timestamp) VALUES (?, ?, ?, ?, ?, ?)",
+        &[&(author as i64), &(peer as i64), &queue_id.map(|id| id as i64), &server, &text, &timestamp]
+    ).unwrap();
+    Ok(())
+}
+fn is_pm_stored(db: &SqlConnection, server: &str, queue_id: usize) -> bool {
+    db.query_row(
+        "SELECT COUNT(*) FROM pm_history WHERE server = ? AND queue_id = ?",
+        &[&server, &(queue_id as i64)],
+        |row| row.get::<_, i64>(0)
+    ).unwrap() > 0
+}
+fn store_token(db: &SqlConnection, server: &str, token: &str) -> Result<(), Box<std::error::Error>> {
+    let token = vault::seal(db, token.as_bytes())?;
+    db.execute("UPDATE servers SET token = ? WHERE ip = ?", &[&token, &server]).unwrap();
+    Ok(())
+}
+// Used for users without prekeys, such as those on older clients
+fn encrypt_pm(db: &SqlConnection, user: &common::User, text: &[u8]) -> Result<Vec<u8>, String> {
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+
+    let public = if let Some(ref public) = user.public_key {
+        public.clone()
+    } else {
+        // Keys exchanged by hand before they were published automatically
+        let mut stmt = db.prepare_cached("SELECT public FROM pms WHERE recipient = ?").unwrap();
+        let mut rows = stmt.query(&[&(user.id as i64)]).unwrap();
+
+        if let Some(row) = rows.next() {
+            row.unwrap().get::<_, String>(0).into_bytes()
+        } else {
+            return Err(format!(
+                "{} hasn't published a key yet. They need to connect with an up to date client, or you can run `/setupkeys {}`",
+                user.name,
+                user.name
+            ));
+        }
+    };
+    let rsa = Rsa::public_key_from_pem(&public)
+        .map_err(|err| format!("Is that valid PEM data? {}", err))?;
+    // Signed with the identity key so the recipient knows it's really you
+    let identity = get_identity(db).and_then(|rsa| Ok(PKey::from_rsa(rsa)?))
+        .map_err(|err| format!("Failed to load your identity key: {}", err))?;
+
+    encrypter::encrypt(text, &rsa, &identity).map_err(|err| err.to_string())
+}
+// Asks for the passphrase until it's correct. Returns false if the user gives up.
+fn unlock(db: &SqlConnection, screen: &frontend::Screen) -> bool {
+    loop {
+        let passphrase = match screen.readpass() {
+            Ok(ok) => ok,
+            Err(_) => return false
+        };
+        match vault::unlock(db, &passphrase) {
+            Ok(true) => return true,
+            Ok(false) => screen.log(String::from("Wrong passphrase. Try again: ")),
+            Err(err) => {
+                screen.log(format!("Failed to unlock: {}", err));
+                return false;
+            }
+        }
+    }
+}
 fn find_user<'a>(users: &'a HashMap<usize, common::User>, name: &str) -> Option<&'a common::User> {
     users.values().find(|user| user.name == name)
 }
-fn to_perm_string(allow: u8, deny: u8) -> String {
+fn get_identity(db: &SqlConnection) -> Result<openssl::rsa::Rsa, Box<std::error::Error>> {
+    use openssl::rsa::Rsa;
+
+    let pem: Option<Vec<u8>> = db.query_row("SELECT value FROM data WHERE key = 'identity'", &[], |row| row.get(0)).ok();
+    if let Some(pem) = pem {
+        return Ok(Rsa::private_key_from_pem(&vault::open(db, &pem)?)?);
+    }
+
+    let rsa = Rsa::generate(3072)?;
+    let pem = vault::seal(db, &rsa.private_key_to_pem()?)?;
+    db.execute("INSERT INTO data (key, value) VALUES ('identity', ?)", &[&pem]).unwrap();
+
+    Ok(rsa)
+}
+fn get_pm_history(db: &SqlConnection, server: &str, peer: usize) -> Vec<(usize, Vec<u8>, i64)> {
+    let mut stmt = db.prepare_cached(
+        "SELECT author, text, timestamp FROM pm_history WHERE server = ? AND peer = ?
+        ORDER BY rowid DESC LIMIT ?"
+    ).unwrap();
+    let rows = stmt.query_map(
+        &[&server, &(peer as i64), &(common::LIMIT_BULK as i64)],
+        |row| (row.get::<_, i64>(0) as usize, row.get::<_, Vec<u8>>(1), row.get::<_, i64>(2))
+    ).unwrap();
+
+    let mut history: Vec<_> = rows
+        .map(|row| {
+            let (author, text, timestamp) = row.unwrap();
+            let text = match vault::open(db, &text) {
+                Ok(text) => text,
+                Err(err) => format!("[couldn't decrypt: {}]", err).into_bytes()
+            };
+            (author, text, timestamp)
+        })
+        .collect();
+    history.reverse();
+    history
+}
+fn get_timestamp() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs() as i64).unwrap_or(0)
+}
+fn get_user_key(session: &Session, id: usize) -> Option<openssl::pkey::PKey> {
+    session.users.get(&id)
+        .and_then(|user| user.public_key.as_ref())
+        .and_then(|public_key| openssl::rsa::Rsa::public_key_from_pem(public_key).ok())
+        .and_then(|rsa| openssl::pkey::PKey::from_rsa(rsa).ok())
+}
+// Same as the server's check, since mentioning more than one user at once needs a permission
+fn can_mention_everyone(session: &Session, msg: &common::Message) -> bool {
+    let author = match session.users.get(&msg.author) {
+        Some(author) => author,
+        None => return false
+    };
+    if author.owner {
+        return true;
+    }
+    let mut groups: Vec<_> = session.groups.values()
+        .filter(|group| group.id == if author.bot { 2 } else { 1 } || author.groups.contains(&group.id))
+        .collect();
+    groups.sort_by_key(|group| group.pos);
+
+    let mut perms = 0;
+    common::perm_apply_iter(&mut perms, &mut groups.iter().map(|group| (group.allow, group.deny)));
+
+    if let Some(channel) = session.channels.get(&msg.channel) {
+        for (role, chan_perms) in &channel.overrides {
+            // The first two groups are the defaults everyone is in
+            if *role <= 2 || author.groups.contains(role) {
+                common::perm_apply(&mut perms, *chan_perms);
+            }
+        }
+    }
+
+    perms & common::PERM_MENTION_EVERYONE == common::PERM_MENTION_EVERYONE
+}
+fn is_mentioned(session: &Session, msg: &common::Message) -> bool {
+    let me = match session.users.get(&session.id) {
+        Some(me) => me,
+        None => return false
+    };
+    let text = String::from_utf8_lossy(&msg.text);
+    let everyone = can_mention_everyone(session, msg);
+
+    common::parse_mentions(&text).into_iter().any(|mention| {
+        mention.to_lowercase() == me.name.to_lowercase()
+            || (everyone && mention == common::MENTION_EVERYONE)
+            || (everyone && session.groups.values().any(|group| {
+                group.name == mention
+                    && (group.id == if me.bot { 2 } else { 1 } || me.groups.contains(&group.id))
+            }))
+    })
+}
+fn to_attachment_string(attachments: &[usize]) -> String {
+    if attachments.is_empty() {
+        return String::new();
+    }
+    let mut result = String::from(" [attachments: ");
+    for (i, id) in attachments.iter().enumerate() {
+        if i != 0 { result.push_str(", "); }
+        result.push('#');
+        result.push_str(&id.to_string());
+    }
+    result.push(']');
+    result
+}
+fn to_reaction_string(reactions: &HashMap<String, usize>) -> String {
+    if reactions.is_empty() {
+        return String::new();
+    }
+    let mut reactions: Vec<_> = reactions.iter().collect();
+    reactions.sort_by_key(|&(emoji, _)| emoji);
+
+    let mut result = String::from(" [");
+    for (i, &(emoji, count)) in reactions.iter().enumerate() {
+        if i != 0 { result.push_str(", "); }
+        result.push_str(emoji);
+        result.push(' ');
+        result.push_str(&count.to_string());
+    }
+    result.push(']');
+    result
+}
+fn to_perm_string(allow: u16, deny: u16) -> String {
     let mut result = String::with_capacity(10);
 
     if allow != 0 {
@@ -861,7 +1937,7 @@ fn to_perm_string(allow: u8, deny: u8) -> String {
     result.shrink_to_fit();
     result
 }
-fn to_single_perm_string(bitmask: u8) -> String {
+fn to_single_perm_string(bitmask: u16) -> String {
     let mut result = String::with_capacity(4);
 
     if bitmask & common::PERM_READ == common::PERM_READ {
@@ -882,10 +1958,16 @@ fn to_single_perm_string(bitmask: u8) -> String {
     if bitmask & common::PERM_MANAGE_MESSAGES == common::PERM_MANAGE_MESSAGES {
         result.push('m');
     }
+    if bitmask & common::PERM_CREATE_INVITES == common::PERM_CREATE_INVITES {
+        result.push('i');
+    }
+    if bitmask & common::PERM_MENTION_EVERYONE == common::PERM_MENTION_EVERYONE {
+        result.push('e');
+    }
 
     result
 }
-fn from_perm_string(input: &str, allow: &mut u8, deny: &mut u8) -> bool {
+fn from_perm_string(input: &str, allow: &mut u16, deny: &mut u16) -> bool {
     let mut mode = '+';
 
     for c in input.chars() {
@@ -901,6 +1983,8 @@ fn from_perm_string(input: &str, allow: &mut u8, deny: &mut u8) -> bool {
             'c' => common::PERM_MANAGE_CHANNELS,
             'g' => common::PERM_MANAGE_GROUPS,
             'm' => common::PERM_MANAGE_MESSAGES,
+            'i' => common::PERM_CREATE_INVITES,
+            'e' => common::PERM_MENTION_EVERYONE,
             ' ' => continue,
             _   => return false
         };
@@ -915,6 +1999,65 @@ fn from_perm_string(input: &str, allow: &mut u8, deny: &mut u8) -> bool {
     true
 }
 
+fn to_presence_string(presence: Option<&common::PresenceReceive>) -> String {
+    let presence = match presence {
+        Some(some) => some,
+        None => return String::from(" [offline]")
+    };
+    let mut result = String::from(match presence.presence {
+        common::Presence::Online => " [online",
+        common::Presence::Idle => " [idle",
+        common::Presence::Offline => " [offline"
+    });
+    if presence.dnd {
+        result.push_str(", do not disturb");
+    }
+    if let Some(ref status) = presence.status {
+        result.push_str(": ");
+        result.push_str(&frontend::sanitize(status.clone()));
+    }
+    result.push(']');
+    result
+}
+fn to_unread_string(unread: Option<&common::UnreadReceive>) -> String {
+    match unread {
+        Some(unread) if unread.mentions > 0 => format!(" ({}, {} mentions)", unread.unread, unread.mentions),
+        Some(unread) if unread.unread > 0 => format!(" ({})", unread.unread),
+        _ => String::new()
+    }
+}
+fn to_relative_time(timestamp: i64) -> String {
+    let seconds = get_timestamp() - timestamp;
+
+    match seconds {
+        n if n < 60 => String::from("just now"),
+        n if n < 60 * 60 => format!("{} minutes ago", n / 60),
+        n if n < 60 * 60 * 24 => format!("{} hours ago", n / (60 * 60)),
+        n => format!("{} days ago", n / (60 * 60 * 24))
+    }
+}
+fn to_optional_string<T: ToString>(input: Option<T>) -> String {
+    input.map(|value| value.to_string()).unwrap_or_else(|| String::from("default"))
+}
+fn parse_clearable(input: &str, current: Option<String>) -> Option<String> {
+    let input = input.trim();
+    match input {
+        "" => current,
+        "-" => None,
+        _ => Some(input.to_string())
+    }
+}
+fn parse_optional<T: std::str::FromStr>(input: &str, current: Option<T>) -> Result<Option<T>, ()> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Ok(current);
+    }
+    if input == "default" {
+        return Ok(None);
+    }
+    input.parse().map(Some).map_err(|_| ())
+}
+
 fn parse_ip(input: &str) -> Option<SocketAddr> {
     let mut parts = input.split(':');
     let ip = match parts.next() {
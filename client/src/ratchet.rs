@@ -0,0 +1,774 @@
+use common::*;
+use encrypter::MAGIC;
+use error::{ErrBounds, ErrPublicKey, ErrSession, ErrSignature, ErrSkip, ErrVersion};
+use openssl::bn::BigNumContext;
+use openssl::ec::{EcGroup, EcKey, EcPoint, POINT_CONVERSION_COMPRESSED, POINT_CONVERSION_UNCOMPRESSED};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid;
+use openssl::pkey::PKey;
+use openssl::sign::{Signer, Verifier};
+use openssl::symm::{self, Cipher};
+use rusqlite::Connection as SqlConnection;
+use std::error::Error;
+use vault;
+
+// Double ratchet sessions set up using X3DH, see https://signal.org/docs/
+const VERSION: u8 = 2;
+
+// How many message keys are kept for messages that haven't arrived yet
+const MAX_SKIP: u32 = 1000;
+// How many one-time prekeys are uploaded on each connect
+const ONE_TIME_AMOUNT: usize = 10;
+// Old signed prekeys are kept a while for sessions started just before they were replaced
+const SIGNED_AMOUNT: usize = 2;
+
+const INFO_MESSAGE: &'static [u8] = b"synac message key";
+const INFO_RATCHET: &'static [u8] = b"synac ratchet";
+const INFO_X3DH:    &'static [u8] = b"synac x3dh";
+const SIGNATURE_IDENTITY: &'static [u8] = b"synac prekey identity";
+const SIGNATURE_SIGNED:   &'static [u8] = b"synac signed prekey";
+
+const SIZE_KEY:   usize = 32;
+const SIZE_NONCE: usize = 12;
+const SIZE_TAG:   usize = 16;
+
+fn group() -> Result<EcGroup, ErrorStack> {
+    EcGroup::from_curve_name(nid::X9_62_PRIME256V1)
+}
+fn hmac(key: &[u8], input: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(input)?;
+    signer.finish()
+}
+// HKDF from RFC 5869, using SHA-256
+fn hkdf(salt: &[u8], input: &[u8], info: &[u8], len: usize) -> Result<Vec<u8>, ErrorStack> {
+    let prk = hmac(salt, input)?;
+
+    let mut output = Vec::with_capacity(len);
+    let mut block = Vec::new();
+    let mut i = 1;
+    while output.len() < len {
+        let mut data = block;
+        data.extend(info);
+        data.push(i);
+
+        block = hmac(&prk, &data)?;
+        output.extend(block.iter());
+        i += 1;
+    }
+    output.truncate(len);
+
+    Ok(output)
+}
+fn kdf_rk(rk: &[u8], dh: &[u8]) -> Result<(Vec<u8>, Vec<u8>), ErrorStack> {
+    let mut rk = hkdf(rk, dh, INFO_RATCHET, SIZE_KEY*2)?;
+    let ck = rk.split_off(SIZE_KEY);
+    Ok((rk, ck))
+}
+fn kdf_ck(ck: &[u8]) -> Result<(Vec<u8>, Vec<u8>), ErrorStack> {
+    Ok((hmac(ck, &[2])?, hmac(ck, &[1])?))
+}
+fn message_key(mk: &[u8]) -> Result<(Vec<u8>, Vec<u8>), ErrorStack> {
+    let mut key = hkdf(&[0; SIZE_KEY], mk, INFO_MESSAGE, SIZE_KEY+SIZE_NONCE)?;
+    let nonce = key.split_off(SIZE_KEY);
+    Ok((key, nonce))
+}
+
+fn write_bytes(output: &mut Vec<u8>, input: &[u8]) {
+    output.extend(encode_u16(input.len() as u16).iter());
+    output.extend(input);
+}
+fn write_u32(output: &mut Vec<u8>, input: u32) {
+    output.extend(encode_u16((input >> 16) as u16).iter());
+    output.extend(encode_u16(input as u16).iter());
+}
+fn non_empty(input: &[u8]) -> Option<Vec<u8>> {
+    if input.is_empty() { None } else { Some(input.to_vec()) }
+}
+fn or_empty(input: &Option<Vec<u8>>) -> &[u8] {
+    input.as_ref().map(|input| &**input).unwrap_or(&[])
+}
+struct Reader<'a> {
+    input: &'a [u8]
+}
+impl<'a> Reader<'a> {
+    fn bytes(&mut self) -> Result<&'a [u8], ErrBounds> {
+        if self.input.len() < 2 {
+            return Err(ErrBounds);
+        }
+        let len = decode_u16(&self.input[..2]) as usize;
+        if self.input.len() < 2+len {
+            return Err(ErrBounds);
+        }
+        let (bytes, input) = self.input[2..].split_at(len);
+        self.input = input;
+        Ok(bytes)
+    }
+    fn u32(&mut self) -> Result<u32, ErrBounds> {
+        if self.input.len() < 4 {
+            return Err(ErrBounds);
+        }
+        let n = (decode_u16(&self.input[..2]) as u32) << 16 | decode_u16(&self.input[2..4]) as u32;
+        self.input = &self.input[4..];
+        Ok(n)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct KeyPair {
+    private: Vec<u8>,
+    public: Vec<u8>
+}
+impl KeyPair {
+    fn generate() -> Result<KeyPair, ErrorStack> {
+        KeyPair::from_key(&EcKey::generate(&group()?)?)
+    }
+    fn from_key(key: &EcKey) -> Result<KeyPair, ErrorStack> {
+        let group = group()?;
+        let mut ctx = BigNumContext::new()?;
+        let public = key.public_key()
+            .expect("EC key without a public key")
+            .to_bytes(&group, POINT_CONVERSION_COMPRESSED, &mut ctx)?;
+
+        Ok(KeyPair {
+            private: key.private_key_to_der()?,
+            public: public
+        })
+    }
+    // ECDH by hand, since this version of openssl has no key derivation.
+    // The shared secret is the x coordinate of the private key times the public point.
+    fn dh(&self, public: &[u8]) -> Result<Vec<u8>, Box<Error>> {
+        let group = group()?;
+        let mut ctx = BigNumContext::new()?;
+        let point = EcPoint::from_bytes(&group, public, &mut ctx)?;
+
+        let private = EcKey::private_key_from_der(&self.private)?;
+        let scalar = private.private_key().expect("EC key without a private key");
+
+        let mut shared = EcPoint::new(&group)?;
+        shared.mul(&group, &point, scalar, &ctx)?;
+
+        // 0x04, then x and y of equal length. Anything else is the point at infinity.
+        let bytes = shared.to_bytes(&group, POINT_CONVERSION_UNCOMPRESSED, &mut ctx)?;
+        if bytes.len() != 1 + 2*SIZE_KEY || bytes[0] != 4 {
+            return Err(Box::new(ErrPublicKey));
+        }
+        Ok(bytes[1..1+SIZE_KEY].to_vec())
+    }
+}
+
+// Everything the other side needs to set up their end of the session
+#[derive(Clone, Debug, Default)]
+struct Init {
+    identity: Vec<u8>,
+    identity_signature: Vec<u8>,
+    ephemeral: Vec<u8>,
+    signed: Vec<u8>,
+    one_time: Vec<u8>
+}
+impl Init {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut output = Vec::new();
+        write_bytes(&mut output, &self.identity);
+        write_bytes(&mut output, &self.identity_signature);
+        write_bytes(&mut output, &self.ephemeral);
+        write_bytes(&mut output, &self.signed);
+        write_bytes(&mut output, &self.one_time);
+        output
+    }
+    fn from_bytes(input: &[u8]) -> Result<Init, ErrBounds> {
+        let mut reader = Reader { input: input };
+        Ok(Init {
+            identity: reader.bytes()?.to_vec(),
+            identity_signature: reader.bytes()?.to_vec(),
+            ephemeral: reader.bytes()?.to_vec(),
+            signed: reader.bytes()?.to_vec(),
+            one_time: reader.bytes()?.to_vec()
+        })
+    }
+}
+
+struct Message<'a> {
+    init: Option<Init>,
+    dh: &'a [u8],
+    pn: u32,
+    n: u32,
+    header: &'a [u8],
+    tag: &'a [u8],
+    encrypted: &'a [u8]
+}
+fn parse(input: &[u8]) -> Result<Message, Box<Error>> {
+    if input.len() < 2 {
+        return Err(Box::new(ErrBounds));
+    }
+    if input[0] != MAGIC || input[1] != VERSION {
+        return Err(Box::new(ErrVersion));
+    }
+
+    let mut reader = Reader { input: &input[2..] };
+    let init = reader.bytes()?;
+    let dh = reader.bytes()?;
+    let pn = reader.u32()?;
+    let n = reader.u32()?;
+    if reader.input.len() < SIZE_TAG {
+        return Err(Box::new(ErrBounds));
+    }
+
+    let (header, input) = input.split_at(input.len() - reader.input.len());
+    let (tag, encrypted) = input.split_at(SIZE_TAG);
+
+    Ok(Message {
+        init: if init.is_empty() { None } else { Some(Init::from_bytes(init)?) },
+        dh: dh,
+        pn: pn,
+        n: n,
+        header: header,
+        tag: tag,
+        encrypted: encrypted
+    })
+}
+pub fn is_ratchet(input: &[u8]) -> bool {
+    input.len() >= 2 && input[0] == MAGIC && input[1] == VERSION
+}
+
+#[derive(Clone, Debug)]
+struct Session {
+    ad: Vec<u8>,
+    dhs: KeyPair,
+    dhr: Option<Vec<u8>>,
+    rk: Vec<u8>,
+    cks: Option<Vec<u8>>,
+    ckr: Option<Vec<u8>>,
+    ns: u32,
+    nr: u32,
+    pn: u32,
+    skipped: Vec<(Vec<u8>, u32, Vec<u8>)>,
+    // Sent along with every message until the other side has replied
+    init: Option<Init>,
+    // The ephemeral key this session was started with, to recognize repeated inits
+    origin: Vec<u8>
+}
+impl Session {
+    fn initiate(
+        identity: &KeyPair,
+        identity_signature: Vec<u8>,
+        remote_identity: &[u8],
+        signed: &[u8],
+        one_time: Option<&[u8]>
+    ) -> Result<Session, Box<Error>> {
+        let ephemeral = KeyPair::generate()?;
+
+        let mut secret = vec![0xFF; SIZE_KEY];
+        secret.extend(identity.dh(signed)?);
+        secret.extend(ephemeral.dh(remote_identity)?);
+        secret.extend(ephemeral.dh(signed)?);
+        if let Some(one_time) = one_time {
+            secret.extend(ephemeral.dh(one_time)?);
+        }
+        let sk = hkdf(&[0; SIZE_KEY], &secret, INFO_X3DH, SIZE_KEY)?;
+
+        let dhs = KeyPair::generate()?;
+        let (rk, cks) = kdf_rk(&sk, &dhs.dh(signed)?)?;
+
+        let mut ad = identity.public.clone();
+        ad.extend(remote_identity);
+
+        Ok(Session {
+            ad: ad,
+            dhs: dhs,
+            dhr: Some(signed.to_vec()),
+            rk: rk,
+            cks: Some(cks),
+            ckr: None,
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            skipped: Vec::new(),
+            init: Some(Init {
+                identity: identity.public.clone(),
+                identity_signature: identity_signature,
+                ephemeral: ephemeral.public.clone(),
+                signed: signed.to_vec(),
+                one_time: one_time.map(|key| key.to_vec()).unwrap_or_default()
+            }),
+            origin: ephemeral.public
+        })
+    }
+    fn respond(
+        identity: &KeyPair,
+        signed: &KeyPair,
+        one_time: Option<&KeyPair>,
+        init: &Init
+    ) -> Result<Session, Box<Error>> {
+        let mut secret = vec![0xFF; SIZE_KEY];
+        secret.extend(signed.dh(&init.identity)?);
+        secret.extend(identity.dh(&init.ephemeral)?);
+        secret.extend(signed.dh(&init.ephemeral)?);
+        if let Some(one_time) = one_time {
+            secret.extend(one_time.dh(&init.ephemeral)?);
+        }
+        let sk = hkdf(&[0; SIZE_KEY], &secret, INFO_X3DH, SIZE_KEY)?;
+
+        let mut ad = init.identity.clone();
+        ad.extend(&identity.public);
+
+        Ok(Session {
+            ad: ad,
+            dhs: signed.clone(),
+            dhr: None,
+            rk: sk,
+            cks: None,
+            ckr: None,
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            skipped: Vec::new(),
+            init: None,
+            origin: init.ephemeral.clone()
+        })
+    }
+    fn encrypt(&mut self, input: &[u8]) -> Result<Vec<u8>, Box<Error>> {
+        let (cks, mk) = kdf_ck(self.cks.as_ref().ok_or(ErrSession)?)?;
+        self.cks = Some(cks);
+
+        let mut output = vec![MAGIC, VERSION];
+        write_bytes(&mut output, &self.init.as_ref().map(|init| init.to_bytes()).unwrap_or_default());
+        write_bytes(&mut output, &self.dhs.public);
+        write_u32(&mut output, self.pn);
+        write_u32(&mut output, self.ns);
+        self.ns += 1;
+
+        let (key, nonce) = message_key(&mk)?;
+        let mut aad = self.ad.clone();
+        aad.extend(&output);
+
+        let mut tag = [0; SIZE_TAG];
+        let mut encrypted = symm::encrypt_aead(Cipher::aes_256_gcm(), &key, Some(&nonce), &aad, input, &mut tag)?;
+
+        output.extend(tag.iter());
+        output.append(&mut encrypted);
+
+        Ok(output)
+    }
+    fn decrypt(&mut self, input: &[u8]) -> Result<Vec<u8>, Box<Error>> {
+        let message = parse(input)?;
+
+        // Work on a copy, so a bad message can't break the session
+        let mut state = self.clone();
+        let decrypted = state.decrypt_message(&message)?;
+        *self = state;
+
+        Ok(decrypted)
+    }
+    fn decrypt_message(&mut self, message: &Message) -> Result<Vec<u8>, Box<Error>> {
+        if let Some(i) = self.skipped.iter().position(|&(ref dh, n, _)| &**dh == message.dh && n == message.n) {
+            let (_, _, mk) = self.skipped.remove(i);
+            return self.open(&mk, message);
+        }
+
+        if self.dhr.as_ref().map(|dhr| &**dhr) != Some(message.dh) {
+            self.skip(message.pn)?;
+            self.step(message.dh)?;
+        }
+        self.skip(message.n)?;
+
+        let (ckr, mk) = kdf_ck(self.ckr.as_ref().ok_or(ErrSession)?)?;
+        self.ckr = Some(ckr);
+        self.nr += 1;
+
+        let decrypted = self.open(&mk, message)?;
+        // They've heard from us, so there's no need to keep setting up the session
+        self.init = None;
+
+        Ok(decrypted)
+    }
+    fn open(&self, mk: &[u8], message: &Message) -> Result<Vec<u8>, Box<Error>> {
+        let (key, nonce) = message_key(mk)?;
+        let mut aad = self.ad.clone();
+        aad.extend(message.header);
+
+        Ok(symm::decrypt_aead(Cipher::aes_256_gcm(), &key, Some(&nonce), &aad, message.encrypted, message.tag)?)
+    }
+    fn skip(&mut self, until: u32) -> Result<(), ErrSkip> {
+        if self.nr + MAX_SKIP < until {
+            return Err(ErrSkip);
+        }
+        if let (Some(mut ckr), Some(dhr)) = (self.ckr.clone(), self.dhr.clone()) {
+            while self.nr < until {
+                let (next, mk) = kdf_ck(&ckr).map_err(|_| ErrSkip)?;
+                self.skipped.push((dhr.clone(), self.nr, mk));
+                ckr = next;
+                self.nr += 1;
+            }
+            self.ckr = Some(ckr);
+        }
+
+        let len = self.skipped.len();
+        if len > MAX_SKIP as usize {
+            self.skipped.drain(..len - MAX_SKIP as usize);
+        }
+        Ok(())
+    }
+    fn step(&mut self, dh: &[u8]) -> Result<(), Box<Error>> {
+        self.pn = self.ns;
+        self.ns = 0;
+        self.nr = 0;
+        self.dhr = Some(dh.to_vec());
+
+        let (rk, ckr) = kdf_rk(&self.rk, &self.dhs.dh(dh)?)?;
+        self.dhs = KeyPair::generate()?;
+        let (rk, cks) = kdf_rk(&rk, &self.dhs.dh(dh)?)?;
+
+        self.rk = rk;
+        self.ckr = Some(ckr);
+        self.cks = Some(cks);
+        Ok(())
+    }
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut output = Vec::new();
+        write_bytes(&mut output, &self.ad);
+        write_bytes(&mut output, &self.dhs.private);
+        write_bytes(&mut output, &self.dhs.public);
+        write_bytes(&mut output, or_empty(&self.dhr));
+        write_bytes(&mut output, &self.rk);
+        write_bytes(&mut output, or_empty(&self.cks));
+        write_bytes(&mut output, or_empty(&self.ckr));
+        write_u32(&mut output, self.ns);
+        write_u32(&mut output, self.nr);
+        write_u32(&mut output, self.pn);
+        write_u32(&mut output, self.skipped.len() as u32);
+        for &(ref dh, n, ref mk) in &self.skipped {
+            write_bytes(&mut output, dh);
+            write_u32(&mut output, n);
+            write_bytes(&mut output, mk);
+        }
+        write_bytes(&mut output, &self.init.as_ref().map(|init| init.to_bytes()).unwrap_or_default());
+        write_bytes(&mut output, &self.origin);
+        output
+    }
+    fn from_bytes(input: &[u8]) -> Result<Session, ErrBounds> {
+        let mut reader = Reader { input: input };
+
+        let ad = reader.bytes()?.to_vec();
+        let dhs = KeyPair {
+            private: reader.bytes()?.to_vec(),
+            public: reader.bytes()?.to_vec()
+        };
+        let dhr = non_empty(reader.bytes()?);
+        let rk = reader.bytes()?.to_vec();
+        let cks = non_empty(reader.bytes()?);
+        let ckr = non_empty(reader.bytes()?);
+        let ns = reader.u32()?;
+        let nr = reader.u32()?;
+        let pn = reader.u32()?;
+
+        let len = reader.u32()?;
+        if len > MAX_SKIP {
+            return Err(ErrBounds);
+        }
+        let mut skipped = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            skipped.push((reader.bytes()?.to_vec(), reader.u32()?, reader.bytes()?.to_vec()));
+        }
+
+        let init = reader.bytes()?;
+        let init = if init.is_empty() { None } else { Some(Init::from_bytes(init)?) };
+
+        Ok(Session {
+            ad: ad,
+            dhs: dhs,
+            dhr: dhr,
+            rk: rk,
+            cks: cks,
+            ckr: ckr,
+            ns: ns,
+            nr: nr,
+            pn: pn,
+            skipped: skipped,
+            init: init,
+            origin: reader.bytes()?.to_vec()
+        })
+    }
+}
+
+fn sign(identity: &PKey, context: &[u8], key: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let mut signer = Signer::new(MessageDigest::sha256(), identity)?;
+    signer.update(context)?;
+    signer.update(key)?;
+    signer.finish()
+}
+fn verify(identity: &PKey, context: &[u8], key: &[u8], signature: &[u8]) -> Result<(), Box<Error>> {
+    let mut verifier = Verifier::new(MessageDigest::sha256(), identity)?;
+    verifier.update(context)?;
+    verifier.update(key)?;
+    if !verifier.finish(signature)? {
+        return Err(Box::new(ErrSignature));
+    }
+    Ok(())
+}
+// The key used for key agreement. Signed by the RSA identity, so it's tied to the published key.
+fn get_identity(db: &SqlConnection) -> Result<KeyPair, Box<Error>> {
+    let pem: Option<Vec<u8>> = db.query_row(
+        "SELECT value FROM data WHERE key = 'ratchet_identity'",
+        &[],
+        |row| row.get(0)
+    ).ok();
+    if let Some(pem) = pem {
+        return Ok(KeyPair::from_key(&EcKey::private_key_from_pem(&vault::open(db, &pem)?)?)?);
+    }
+
+    let key = EcKey::generate(&group()?)?;
+    let pem = vault::seal(db, &key.private_key_to_pem()?)?;
+    db.execute("INSERT INTO data (key, value) VALUES ('ratchet_identity', ?)", &[&pem]).unwrap();
+
+    Ok(KeyPair::from_key(&key)?)
+}
+fn get_prekey(db: &SqlConnection, server: &str, public: &[u8], signed: bool) -> Option<KeyPair> {
+    db.query_row(
+        "SELECT private FROM prekeys WHERE server = ? AND public = ? AND signed = ?",
+        &[&server, &public, &signed],
+        |row| row.get::<_, Vec<u8>>(0)
+    ).ok()
+        .and_then(|private| vault::open(db, &private).ok())
+        .map(|private| KeyPair {
+            private: private,
+            public: public.to_vec()
+        })
+}
+fn store_prekey(db: &SqlConnection, server: &str, key: &KeyPair, signed: bool) -> Result<(), Box<Error>> {
+    db.execute(
+        "INSERT INTO prekeys (private, public, server, signed) VALUES (?, ?, ?, ?)",
+        &[&vault::seal(db, &key.private)?, &key.public, &server, &signed]
+    ).unwrap();
+    Ok(())
+}
+fn load(db: &SqlConnection, server: &str, peer: usize) -> Result<Option<Session>, Box<Error>> {
+    let state: Option<Vec<u8>> = db.query_row(
+        "SELECT state FROM ratchets WHERE server = ? AND peer = ?",
+        &[&server, &(peer as i64)],
+        |row| row.get(0)
+    ).ok();
+    match state {
+        Some(state) => Ok(Some(Session::from_bytes(&vault::open(db, &state)?)?)),
+        None => Ok(None)
+    }
+}
+fn store(db: &SqlConnection, server: &str, peer: usize, session: &Session) -> Result<(), Box<Error>> {
+    db.execute(
+        "REPLACE INTO ratchets (peer, server, state) VALUES (?, ?, ?)",
+        &[&(peer as i64), &server, &vault::seal(db, &session.to_bytes())?]
+    ).unwrap();
+    Ok(())
+}
+
+pub fn has_session(db: &SqlConnection, server: &str, peer: usize) -> bool {
+    db.query_row(
+        "SELECT COUNT(*) FROM ratchets WHERE server = ? AND peer = ?",
+        &[&server, &(peer as i64)],
+        |row| row.get::<_, i64>(0)
+    ).unwrap() > 0
+}
+// Creates a new signed prekey and a batch of one-time prekeys, ready to send to the server
+pub fn publish(db: &SqlConnection, server: &str, identity: &PKey) -> Result<PrekeyPublish, Box<Error>> {
+    let own = get_identity(db)?;
+    let signed = KeyPair::generate()?;
+    store_prekey(db, server, &signed, true)?;
+
+    let mut one_time = Vec::with_capacity(ONE_TIME_AMOUNT);
+    for _ in 0..ONE_TIME_AMOUNT {
+        let key = KeyPair::generate()?;
+        store_prekey(db, server, &key, false)?;
+        one_time.push(key.public);
+    }
+
+    Ok(PrekeyPublish {
+        inner: Prekeys {
+            identity_signature: sign(identity, SIGNATURE_IDENTITY, &own.public)?,
+            identity: own.public,
+            signed_signature: sign(identity, SIGNATURE_SIGNED, &signed.public)?,
+            signed: signed.public
+        },
+        one_time: one_time
+    })
+}
+// Only call this once the server confirmed the publish, or it could still hand out the deleted prekeys
+pub fn prune(db: &SqlConnection, server: &str) {
+    // The server throws away the oldest one-time prekeys, so they'll never be used
+    for &(signed, amount) in &[(true, SIGNED_AMOUNT), (false, LIMIT_PREKEY_AMOUNT)] {
+        db.execute(
+            "DELETE FROM prekeys WHERE server = ? AND signed = ? AND rowid NOT IN (
+                SELECT rowid FROM prekeys WHERE server = ? AND signed = ? ORDER BY rowid DESC LIMIT ?
+            )",
+            &[&server, &signed, &server, &signed, &(amount as i64)]
+        ).unwrap();
+    }
+}
+// Starts a session using the prekeys of `peer`, which must be signed by their published key
+pub fn start(
+    db: &SqlConnection,
+    server: &str,
+    peer: usize,
+    prekeys: &Prekeys,
+    one_time: Option<&[u8]>,
+    peer_key: &PKey,
+    identity: &PKey
+) -> Result<(), Box<Error>> {
+    verify(peer_key, SIGNATURE_IDENTITY, &prekeys.identity, &prekeys.identity_signature)?;
+    verify(peer_key, SIGNATURE_SIGNED, &prekeys.signed, &prekeys.signed_signature)?;
+
+    let own = get_identity(db)?;
+    let signature = sign(identity, SIGNATURE_IDENTITY, &own.public)?;
+    let session = Session::initiate(&own, signature, &prekeys.identity, &prekeys.signed, one_time)?;
+    store(db, server, peer, &session)?;
+
+    Ok(())
+}
+pub fn encrypt(db: &SqlConnection, server: &str, peer: usize, input: &[u8]) -> Result<Vec<u8>, Box<Error>> {
+    let mut session = load(db, server, peer)?.ok_or(ErrSession)?;
+    let encrypted = session.encrypt(input)?;
+    store(db, server, peer, &session)?;
+
+    Ok(encrypted)
+}
+pub fn decrypt(
+    db: &SqlConnection,
+    server: &str,
+    peer: usize,
+    peer_key: Option<&PKey>,
+    input: &[u8]
+) -> Result<Vec<u8>, Box<Error>> {
+    let message = parse(input)?;
+    let existing = load(db, server, peer)?;
+
+    let mut used = None;
+    let mut session = match message.init {
+        Some(ref init) if existing.as_ref().map(|session| &session.origin) != Some(&init.ephemeral) => {
+            // Either the first message, or they lost the session and started over
+            verify(peer_key.ok_or(ErrSignature)?, SIGNATURE_IDENTITY, &init.identity, &init.identity_signature)?;
+
+            let own = get_identity(db)?;
+            let signed = get_prekey(db, server, &init.signed, true).ok_or(ErrSession)?;
+            let one_time = if init.one_time.is_empty() {
+                None
+            } else {
+                used = Some(&init.one_time);
+                Some(get_prekey(db, server, &init.one_time, false).ok_or(ErrSession)?)
+            };
+
+            Session::respond(&own, &signed, one_time.as_ref(), init)?
+        },
+        _ => existing.ok_or(ErrSession)?
+    };
+
+    let decrypted = session.decrypt(input)?;
+    store(db, server, peer, &session)?;
+    if let Some(one_time) = used {
+        // One-time prekeys are exactly that
+        db.execute(
+            "DELETE FROM prekeys WHERE server = ? AND public = ? AND signed = 0",
+            &[&server, one_time]
+        ).unwrap();
+    }
+
+    Ok(decrypted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sessions() -> (Session, Session) {
+        let alice = KeyPair::generate().unwrap();
+        let bob = KeyPair::generate().unwrap();
+        let signed = KeyPair::generate().unwrap();
+        let one_time = KeyPair::generate().unwrap();
+
+        let mut alice = Session::initiate(&alice, Vec::new(), &bob.public, &signed.public, Some(&one_time.public)).unwrap();
+
+        let first = alice.encrypt(b"Hello, Bob!").unwrap();
+        let init = parse(&first).unwrap().init.unwrap();
+        let mut bob = Session::respond(&bob, &signed, Some(&one_time), &init).unwrap();
+        assert_eq!(bob.decrypt(&first).unwrap(), b"Hello, Bob!");
+
+        (alice, bob)
+    }
+
+    #[test]
+    fn out_of_order() {
+        let (mut alice, mut bob) = sessions();
+
+        // Messages sent in both directions, with the replies of one turn
+        // arriving before the other side's messages of the previous turn
+        let mut transport: Vec<(bool, String, Vec<u8>)> = Vec::new();
+        for turn in 0..4 {
+            for i in 0..3 {
+                let text = format!("turn {}, message {}", turn, i);
+                if turn % 2 == 0 {
+                    transport.push((true, text.clone(), alice.encrypt(text.as_bytes()).unwrap()));
+                } else {
+                    transport.push((false, text.clone(), bob.encrypt(text.as_bytes()).unwrap()));
+                }
+            }
+            if turn == 0 {
+                // Bob can't reply before he's heard anything new from Alice
+                let (_, ref text, ref encrypted) = transport[0];
+                assert_eq!(bob.decrypt(encrypted).unwrap(), text.as_bytes());
+            }
+            if turn == 1 {
+                // Both ends survive being saved and loaded
+                alice = Session::from_bytes(&alice.to_bytes()).unwrap();
+                bob = Session::from_bytes(&bob.to_bytes()).unwrap();
+            }
+        }
+        transport.remove(0);
+        transport.reverse();
+
+        for (to_bob, text, encrypted) in transport {
+            let recipient = if to_bob { &mut bob } else { &mut alice };
+            assert_eq!(recipient.decrypt(&encrypted).unwrap(), text.as_bytes());
+        }
+    }
+    #[test]
+    fn replay() {
+        let (mut alice, mut bob) = sessions();
+
+        let encrypted = bob.encrypt(b"Hello, Alice!").unwrap();
+        assert_eq!(alice.decrypt(&encrypted).unwrap(), b"Hello, Alice!");
+        assert!(alice.decrypt(&encrypted).is_err());
+
+        // Once Alice has heard back, the session no longer needs setting up
+        let encrypted = alice.encrypt(b"Hi!").unwrap();
+        assert!(parse(&encrypted).unwrap().init.is_none());
+        assert_eq!(bob.decrypt(&encrypted).unwrap(), b"Hi!");
+        assert!(bob.decrypt(&encrypted).is_err());
+    }
+    #[test]
+    fn tampered() {
+        let (mut alice, mut bob) = sessions();
+
+        let first = bob.encrypt(b"first").unwrap();
+        let second = bob.encrypt(b"second").unwrap();
+
+        for i in 0..second.len() {
+            let mut modified = second.clone();
+            modified[i] ^= 1;
+            assert!(alice.decrypt(&modified).is_err());
+        }
+
+        // None of that broke the session
+        assert_eq!(alice.decrypt(&second).unwrap(), b"second");
+        assert_eq!(alice.decrypt(&first).unwrap(), b"first");
+    }
+    #[test]
+    fn skip_limit() {
+        let (mut alice, mut bob) = sessions();
+
+        let mut last = Vec::new();
+        for _ in 0..MAX_SKIP+2 {
+            last = bob.encrypt(b"spam").unwrap();
+        }
+        assert!(alice.decrypt(&last).is_err());
+    }
+}
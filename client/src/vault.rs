@@ -0,0 +1,168 @@
+use error::{ErrBounds, ErrLocked};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkcs5;
+use openssl::rand;
+use openssl::symm::{self, Cipher};
+use rusqlite::Connection as SqlConnection;
+use std::error::Error;
+
+// Makes guessing the passphrase slow
+const ITERATIONS: usize = 100_000;
+
+const SIZE_KEY:   usize = 32;
+const SIZE_NONCE: usize = 12;
+const SIZE_SALT:  usize = 16;
+const SIZE_TAG:   usize = 16;
+
+// Everything that could be used to log in as you or read your messages, as (table, column, filter)
+const SECRETS: &'static [(&'static str, &'static str, &'static str)] = &[
+    ("channel_keys", "key",     "1"),
+    ("data",         "value",   "key IN ('identity', 'ratchet_identity')"),
+    ("pm_history",   "text",    "1"),
+    ("pms",          "private", "1"),
+    ("prekeys",      "private", "1"),
+    ("ratchets",     "state",   "1"),
+    ("servers",      "token",   "token IS NOT NULL")
+];
+
+fn derive(passphrase: &str, salt: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let mut key = vec![0; SIZE_KEY];
+    pkcs5::pbkdf2_hmac(passphrase.as_bytes(), salt, ITERATIONS, MessageDigest::sha256(), &mut key)?;
+    Ok(key)
+}
+fn seal_with(key: &[u8], input: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let mut nonce = [0; SIZE_NONCE];
+    let mut tag   = [0; SIZE_TAG];
+    rand::rand_bytes(&mut nonce)?;
+
+    let mut encrypted_aes = symm::encrypt_aead(Cipher::aes_256_gcm(), key, Some(&nonce), &[], input, &mut tag)?;
+
+    let mut encrypted = Vec::with_capacity(SIZE_NONCE+SIZE_TAG+input.len());
+    encrypted.extend(nonce.iter());
+    encrypted.extend(tag.iter());
+    encrypted.append(&mut encrypted_aes);
+
+    Ok(encrypted)
+}
+fn open_with(key: &[u8], input: &[u8]) -> Result<Vec<u8>, Box<Error>> {
+    if input.len() < SIZE_NONCE+SIZE_TAG {
+        return Err(Box::new(ErrBounds));
+    }
+    let (nonce, rest) = input.split_at(SIZE_NONCE);
+    let (tag, encrypted) = rest.split_at(SIZE_TAG);
+
+    Ok(symm::decrypt_aead(Cipher::aes_256_gcm(), key, Some(nonce), &[], encrypted, tag)?)
+}
+fn get_data(db: &SqlConnection, key: &str) -> Option<Vec<u8>> {
+    db.query_row("SELECT value FROM data WHERE key = ?", &[&key], |row| row.get(0)).ok()
+}
+// The key is kept in a temporary table, which is private to this connection and never written to disk
+fn get_key(db: &SqlConnection) -> Result<Option<Vec<u8>>, ErrLocked> {
+    if !is_enabled(db) {
+        return Ok(None);
+    }
+    db.query_row("SELECT key FROM temp.vault", &[], |row| row.get(0))
+        .map(Some)
+        .map_err(|_| ErrLocked)
+}
+fn set_key(db: &SqlConnection, key: &[u8]) {
+    db.execute_batch("DROP TABLE IF EXISTS temp.vault;
+                      CREATE TEMP TABLE vault (key BLOB NOT NULL);").unwrap();
+    db.execute("INSERT INTO temp.vault (key) VALUES (?)", &[&key]).unwrap();
+}
+
+pub fn is_enabled(db: &SqlConnection) -> bool {
+    get_data(db, "vault_salt").is_some()
+}
+// Returns false if the passphrase is wrong
+pub fn unlock(db: &SqlConnection, passphrase: &str) -> Result<bool, ErrorStack> {
+    let salt = match get_data(db, "vault_salt") {
+        Some(salt) => salt,
+        None => return Ok(true)
+    };
+    let check = get_data(db, "vault_check").unwrap_or_default();
+
+    let key = derive(passphrase, &salt)?;
+    if open_with(&key, &check).is_err() {
+        return Ok(false);
+    }
+    set_key(db, &key);
+
+    Ok(true)
+}
+pub fn lock(db: &SqlConnection) {
+    db.execute("DROP TABLE IF EXISTS temp.vault", &[]).unwrap();
+}
+pub fn seal(db: &SqlConnection, input: &[u8]) -> Result<Vec<u8>, Box<Error>> {
+    match get_key(db)? {
+        Some(key) => Ok(seal_with(&key, input)?),
+        None => Ok(input.to_vec())
+    }
+}
+pub fn open(db: &SqlConnection, input: &[u8]) -> Result<Vec<u8>, Box<Error>> {
+    match get_key(db)? {
+        Some(key) => open_with(&key, input),
+        None => Ok(input.to_vec())
+    }
+}
+// Encrypts every secret again with a new passphrase, or stores them unencrypted if there is none
+pub fn set_passphrase(db: &mut SqlConnection, passphrase: Option<&str>) -> Result<(), Box<Error>> {
+    let tx = db.transaction()?;
+
+    let mut secrets = Vec::new();
+    for &(table, column, filter) in SECRETS {
+        let mut stmt = tx.prepare(&format!("SELECT rowid, {} FROM {} WHERE {}", column, table, filter))?;
+        let mut rows = stmt.query(&[])?;
+
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let rowid: i64 = row.get(0);
+            let value: Vec<u8> = row.get(1);
+            secrets.push((table, column, rowid, open(&tx, &value)?));
+        }
+    }
+
+    tx.execute("DELETE FROM data WHERE key IN ('vault_check', 'vault_salt')", &[])?;
+    lock(&tx);
+    if let Some(passphrase) = passphrase {
+        let mut salt = vec![0; SIZE_SALT];
+        rand::rand_bytes(&mut salt)?;
+        let key = derive(passphrase, &salt)?;
+
+        tx.execute("INSERT INTO data (key, value) VALUES ('vault_check', ?)", &[&seal_with(&key, &[])?])?;
+        tx.execute("INSERT INTO data (key, value) VALUES ('vault_salt', ?)", &[&salt])?;
+        set_key(&tx, &key);
+    }
+
+    for (table, column, rowid, value) in secrets {
+        tx.execute(
+            &format!("UPDATE {} SET {} = ? WHERE rowid = ?", table, column),
+            &[&seal(&tx, &value)?, &rowid]
+        )?;
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let key = [1; SIZE_KEY];
+
+        let encrypted = seal_with(&key, b"hunter2").unwrap();
+        assert_eq!(open_with(&key, &encrypted).unwrap(), b"hunter2");
+        assert!(open_with(&[2; SIZE_KEY], &encrypted).is_err());
+        assert!(open_with(&key, &encrypted[..SIZE_NONCE]).is_err());
+
+        for i in 0..encrypted.len() {
+            let mut modified = encrypted.clone();
+            modified[i] ^= 1;
+            assert!(open_with(&key, &modified).is_err());
+        }
+    }
+}
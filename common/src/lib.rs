@@ -6,12 +6,32 @@ use std::io;
 
 pub const DEFAULT_PORT: u16  = 8439;
 pub const TYPING_TIMEOUT: u8 = 10;
+pub const MENTION_EVERYONE: &'static str = "everyone";
 
+pub const LIMIT_ATTACHMENT:        usize = 64 * 1024 * 1024;
+pub const LIMIT_ATTACHMENT_AMOUNT: usize = 8;
+pub const LIMIT_ATTACHMENT_CHUNK:  usize = 16384;
+pub const LIMIT_ATTACHMENT_NAME:   usize = 128;
+
+pub const LIMIT_TOKEN_LABEL:  usize = 128;
 pub const LIMIT_USER_NAME:    usize = 128;
 pub const LIMIT_CHANNEL_NAME: usize = 128;
+pub const LIMIT_CHANNEL_TOPIC: usize = 1024;
+pub const LIMIT_SLOW_MODE:    u64   = 60*60*6;
+pub const LIMIT_RETENTION_AGE: u64  = 60*60*24*365*100;
+pub const LIMIT_RETENTION_COUNT: usize = 1_000_000_000;
+pub const LIMIT_INVITE_AGE:   u64   = 60*60*24*365;
 pub const LIMIT_GROUP_NAME:   usize = 128;
 pub const LIMIT_GROUP_AMOUNT: usize = 2048;
 pub const LIMIT_MESSAGE:      usize = 16384;
+pub const LIMIT_REACTION:     usize = 32;
+pub const LIMIT_STATUS:       usize = 128;
+pub const LIMIT_PROFILE_BIO:  usize = 1024;
+pub const LIMIT_PROFILE_FIELD: usize = 128;
+pub const LIMIT_PUBLIC_KEY:   usize = 4096;
+pub const LIMIT_PREKEY:       usize = 1024;
+pub const LIMIT_PREKEY_AMOUNT: usize = 100;
+pub const LIMIT_CHANNEL_KEY:  usize = 2048;
 
 pub const LIMIT_BULK:         usize = 64;
 
@@ -30,63 +50,183 @@ pub const ERR_UNKNOWN_CHANNEL:    u8 = 12;
 pub const ERR_UNKNOWN_GROUP:      u8 = 13;
 pub const ERR_UNKNOWN_MESSAGE:    u8 = 14;
 pub const ERR_UNKNOWN_USER:       u8 = 15;
+pub const ERR_UNKNOWN_ATTACHMENT: u8 = 16;
+pub const ERR_ATTACHMENT_INVALID_CHUNK: u8 = 17;
+pub const ERR_UNKNOWN_TOKEN:      u8 = 18;
+pub const ERR_REGISTRATION_CLOSED: u8 = 19;
+pub const ERR_INVITE_INVALID:     u8 = 20;
+pub const ERR_LOGIN_LOCKED:       u8 = 21;
+pub const ERR_PASSWORD_WEAK:      u8 = 22;
+pub const ERR_CHANNEL_INVALID_POS: u8 = 23;
+pub const ERR_INVALID_REACTION:   u8 = 24;
+pub const ERR_KEY_MISMATCH:       u8 = 25;
 
-pub const PERM_READ:              u8 = 1;
-pub const PERM_WRITE:             u8 = 1 << 1;
+pub const PERM_READ:              u16 = 1;
+pub const PERM_WRITE:             u16 = 1 << 1;
 
-pub const PERM_ASSIGN_GROUPS:     u8 = 1 << 2;
-pub const PERM_BAN:               u8 = 1 << 3;
-pub const PERM_MANAGE_CHANNELS:   u8 = 1 << 4;
-pub const PERM_MANAGE_GROUPS:     u8 = 1 << 5;
-pub const PERM_MANAGE_MESSAGES:   u8 = 1 << 6;
+pub const PERM_ASSIGN_GROUPS:     u16 = 1 << 2;
+pub const PERM_BAN:               u16 = 1 << 3;
+pub const PERM_MANAGE_CHANNELS:   u16 = 1 << 4;
+pub const PERM_MANAGE_GROUPS:     u16 = 1 << 5;
+pub const PERM_MANAGE_MESSAGES:   u16 = 1 << 6;
+pub const PERM_CREATE_INVITES:    u16 = 1 << 7;
+pub const PERM_MENTION_EVERYONE:  u16 = 1 << 8;
 
 // TYPES
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Attachment {
+    pub author: usize,
+    pub id: usize,
+    pub name: String,
+    pub size: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Channel {
+    // Channels with the same category are shown together
+    pub category: Option<String>,
+    // Messages are end-to-end encrypted, the server can't read them
+    pub encrypted: bool,
     pub id: usize,
     pub name: String,
-    pub overrides: HashMap<usize, (u8, u8)>
+    pub overrides: HashMap<usize, (u16, u16)>,
+    pub pos: usize,
+    // None uses the server default, Some(0) keeps messages forever
+    pub retention_max_age: Option<u64>,
+    pub retention_max_count: Option<usize>,
+    // Seconds between messages per user, 0 turns it off
+    pub slow_mode: u64,
+    pub topic: Option<String>
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Group {
-    pub allow: u8,
-    pub deny: u8,
+    pub allow: u16,
+    pub deny: u16,
     pub id: usize,
     pub name: String,
     pub pos: usize,
     pub unassignable: bool
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Invite {
+    pub author: usize,
+    pub code: String,
+    pub expires: Option<i64>,
+    pub id: usize,
+    pub max_uses: Option<usize>,
+    pub uses: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Message {
+    pub attachments: Vec<usize>,
     pub author: usize,
     pub channel: usize,
     pub id: usize,
+    pub reactions: HashMap<String, usize>,
     pub text: Vec<u8>,
     pub timestamp: i64,
     pub timestamp_edit: Option<i64>
 }
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Presence {
+    Online,
+    Idle,
+    Offline
+}
+impl Default for Presence {
+    fn default() -> Presence {
+        Presence::Offline
+    }
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Prekeys {
+    // Key used for key agreement, and the identity key's signature of it
+    pub identity: Vec<u8>,
+    pub identity_signature: Vec<u8>,
+    pub signed: Vec<u8>,
+    pub signed_signature: Vec<u8>
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Profile {
+    pub bio: Option<String>,
+    pub display_name: Option<String>,
+    pub pronouns: Option<String>,
+    pub timezone: Option<String>
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Token {
+    pub current: bool,
+    pub id: usize,
+    pub label: String,
+    pub last_ip: String,
+    pub timestamp: i64,
+    pub timestamp_used: i64
+}
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct User {
     pub ban: bool,
     pub bot: bool,
     pub groups: Vec<usize>,
     pub id: usize,
-    pub name: String
+    pub name: String,
+    pub owner: bool,
+    pub profile: Profile,
+    // PEM encoded identity key used for private messages
+    pub public_key: Option<Vec<u8>>
 }
 
 // CLIENT PACKETS
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AccountDelete {
+    pub password: String
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AccountExport {
+    pub offset: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AckChannel {
+    pub channel: usize,
+    pub message: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AttachmentDownload {
+    pub id: usize,
+    pub offset: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AttachmentUpload {
+    pub data: Vec<u8>,
+    pub name: String,
+    pub offset: usize,
+    pub size: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Close {}
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct ChannelCreate {
+    pub category: Option<String>,
+    pub encrypted: bool,
     pub name: String,
-    pub overrides: HashMap<usize, (u8, u8)>
+    pub overrides: HashMap<usize, (u16, u16)>,
+    pub pos: usize
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct ChannelDelete {
     pub id: usize
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ChannelKeyPublish {
+    pub channel: usize,
+    // The key encrypted for each member
+    pub packages: HashMap<usize, Vec<u8>>
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ChannelReorder {
+    // Every channel, in the new order
+    pub ids: Vec<usize>
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct ChannelUpdate {
     pub inner: Channel,
     pub keep_overrides: bool
@@ -98,8 +238,8 @@ pub struct Command {
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct GroupCreate {
-    pub allow: u8,
-    pub deny: u8,
+    pub allow: u16,
+    pub deny: u16,
     pub name: String,
     pub pos: usize,
     pub unassignable: bool
@@ -113,8 +253,25 @@ pub struct GroupUpdate {
     pub inner: Group
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct InviteCreate {
+    pub max_age: Option<u64>,
+    pub max_uses: Option<usize>
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct InviteList {}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct KeyPublish {
+    // Required when rotating, so the key and the prekeys signed by it are replaced together
+    pub prekeys: Option<PrekeyPublish>,
+    pub public_key: Vec<u8>,
+    // Replaces a different key published by another device
+    pub rotate: bool
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Login {
     pub bot: bool,
+    pub device: Option<String>,
+    pub invite: Option<String>,
     pub name: String,
     pub password: Option<String>,
     pub token: Option<String>
@@ -128,6 +285,7 @@ pub struct LoginUpdate {
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct MessageCreate {
+    pub attachments: Vec<usize>,
     pub channel: usize,
     pub text: Vec<u8>
 }
@@ -148,16 +306,82 @@ pub struct MessageList {
     pub limit: usize
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MessagePin {
+    pub id: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MessageSearch {
+    pub after: Option<i64>,
+    pub author: Option<usize>,
+    pub before: Option<i64>,
+    pub channel: Option<usize>,
+    pub limit: usize,
+    pub query: String
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MessageUnpin {
+    pub id: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct MessageUpdate {
     pub id: usize,
     pub text: Vec<u8>
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct OwnershipTransfer {
+    pub id: usize,
+    // Stay owner as well, instead of handing it over
+    pub keep: bool,
+    pub password: String
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PinList {
+    pub channel: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PMAck {
+    pub id: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PrekeyPublish {
+    pub inner: Prekeys,
+    pub one_time: Vec<Vec<u8>>
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PrekeyRequest {
+    pub user: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct PrivateMessage {
     pub text: Vec<u8>,
     pub recipient: usize
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ProfileUpdate {
+    pub inner: Profile
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ReactionAdd {
+    pub emoji: String,
+    pub message: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ReactionRemove {
+    pub emoji: String,
+    pub message: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct StatusUpdate {
+    pub dnd: bool,
+    pub text: Option<String>
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TokenList {}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TokenRevoke {
+    pub id: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Typing {
     pub channel: usize
 }
@@ -170,10 +394,43 @@ pub struct UserUpdate {
 
 // SERVER PACKETS
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AccountExportReceive {
+    pub data: Vec<u8>,
+    pub offset: usize,
+    pub size: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AttachmentChunkReceive {
+    pub data: Vec<u8>,
+    pub id: usize,
+    pub offset: usize,
+    pub size: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AttachmentReceive {
+    pub inner: Attachment
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct ChannelDeleteReceive {
     pub inner: Channel
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ChannelKeyDeliveryReceive {
+    // The key was stored, so messages encrypted with it can be sent now
+    pub channel: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ChannelKeyReceive {
+    pub author: usize,
+    pub channel: usize,
+    pub data: Vec<u8>
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ChannelMembersReceive {
+    pub channel: usize,
+    pub members: Vec<usize>
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct ChannelReceive {
     pub inner: Channel
 }
@@ -192,12 +449,20 @@ pub struct GroupReceive {
     pub new: bool
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct InviteReceive {
+    pub inner: Invite
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct LoginSuccess {
     pub created: bool,
     pub id: usize,
     pub token: String
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MentionReceive {
+    pub inner: Message
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct MessageDeleteReceive {
     pub id: usize
 }
@@ -207,9 +472,74 @@ pub struct MessageReceive {
     pub new: bool
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PinDeleteReceive {
+    pub channel: usize,
+    pub id: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PinReceive {
+    pub inner: Message,
+    pub new: bool
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PMDeliveryReceive {
+    // False if the recipient was offline and will get it when they log in
+    pub delivered: bool,
+    pub recipient: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct PMReceive {
     pub author: usize,
-    pub text: Vec<u8>
+    // Only set for messages queued while you were offline. Those need a PMAck.
+    pub id: Option<usize>,
+    pub text: Vec<u8>,
+    pub timestamp: i64
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PrekeyReceive {
+    // None if the user never published any
+    pub inner: Option<Prekeys>,
+    pub one_time: Option<Vec<u8>>,
+    pub user: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PrekeyStatusReceive {
+    // True when confirming your own publish, so the prekeys it replaced can be deleted.
+    // Otherwise the server is running low on one-time prekeys and wants new ones.
+    pub published: bool
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PresenceReceive {
+    pub dnd: bool,
+    pub id: usize,
+    pub presence: Presence,
+    pub status: Option<String>
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ReactionDeleteReceive {
+    pub author: usize,
+    pub channel: usize,
+    pub emoji: String,
+    pub message: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ReactionReceive {
+    pub author: usize,
+    pub channel: usize,
+    pub emoji: String,
+    pub message: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SearchReceive {
+    pub inner: Message
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TokenDeleteReceive {
+    pub id: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TokenReceive {
+    pub inner: Token
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct TypingReceive {
@@ -217,6 +547,17 @@ pub struct TypingReceive {
     pub channel: usize
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct UnreadReceive {
+    pub channel: usize,
+    pub last_read: Option<usize>,
+    pub mentions: usize,
+    pub unread: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct UserDeleteReceive {
+    pub id: usize
+}
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct UserReceive {
     pub inner: User
 }
@@ -228,40 +569,86 @@ macro_rules! packet {
         pub enum Packet {
             Close,
             Err(u8),
-            RateLimited(u64),
+            // Seconds left, and the channel if it's because of slow mode
+            RateLimited(u64, Option<usize>),
             $($type($type),)+
         }
     }
 }
 packet! (
+    AccountDelete,
+    AccountExport,
+    AckChannel,
+    AttachmentDownload,
+    AttachmentUpload,
     ChannelCreate,
     ChannelDelete,
+    ChannelKeyPublish,
+    ChannelReorder,
     ChannelUpdate,
     Command,
     GroupCreate,
     GroupDelete,
     GroupUpdate,
+    InviteCreate,
+    InviteList,
+    KeyPublish,
     Login,
     LoginUpdate,
     MessageCreate,
     MessageDelete,
     MessageDeleteBulk,
     MessageList,
+    MessagePin,
+    MessageSearch,
+    MessageUnpin,
     MessageUpdate,
+    OwnershipTransfer,
+    PinList,
+    PMAck,
+    PrekeyPublish,
+    PrekeyRequest,
     PrivateMessage,
+    ProfileUpdate,
+    ReactionAdd,
+    ReactionRemove,
+    StatusUpdate,
+    TokenList,
+    TokenRevoke,
     Typing,
     UserUpdate,
 
+    AccountExportReceive,
+    AttachmentChunkReceive,
+    AttachmentReceive,
     ChannelDeleteReceive,
+    ChannelKeyDeliveryReceive,
+    ChannelKeyReceive,
+    ChannelMembersReceive,
     ChannelReceive,
     CommandReceive,
     GroupDeleteReceive,
     GroupReceive,
+    InviteReceive,
     LoginSuccess,
+    MentionReceive,
     MessageDeleteReceive,
     MessageReceive,
+    PinDeleteReceive,
+    PinReceive,
+    PMDeliveryReceive,
     PMReceive,
+    PrekeyReceive,
+    PrekeyStatusReceive,
+    PresenceReceive,
+    ReactionDeleteReceive,
+    ReactionReceive,
+    SearchReceive,
+    TokenDeleteReceive,
+    TokenReceive,
     TypingReceive,
+    UnreadReceive,
+    UserDeleteReceive,
     UserReceive
 );
 
@@ -354,13 +741,42 @@ pub fn write<T: io::Write>(writer: &mut T, packet: &Packet) -> Result<(), Error>
     Ok(())
 }
 
-pub fn perm_apply_iter<I: Iterator<Item = (u8, u8)>>(into: &mut u8, groups: &mut I) {
+pub fn perm_apply_iter<I: Iterator<Item = (u16, u16)>>(into: &mut u16, groups: &mut I) {
     // Expects groups to be sorted
     for group in groups {
         perm_apply(into, group);
     }
 }
-pub fn perm_apply(into: &mut u8, (allow, deny): (u8, u8)) {
+pub fn perm_apply(into: &mut u16, (allow, deny): (u16, u16)) {
     *into |= allow;
     *into &= !deny;
 }
+
+/// Returns every name mentioned with @ in the text, without the @
+pub fn parse_mentions(text: &str) -> Vec<&str> {
+    let mut mentions = Vec::new();
+
+    for word in text.split_whitespace() {
+        if !word.starts_with('@') {
+            continue;
+        }
+        let name = word[1..].trim_right_matches(|c| match c {
+            ',' | '.' | ':' | ';' | '!' | '?' | ')' => true,
+            _ => false
+        });
+        if !name.is_empty() && !mentions.contains(&name) {
+            mentions.push(name);
+        }
+    }
+
+    mentions
+}
+
+#[cfg(test)]
+#[test]
+fn test_mentions() {
+    assert_eq!(parse_mentions("hello world"), Vec::<&str>::new());
+    assert_eq!(parse_mentions("hello @world"), &["world"]);
+    assert_eq!(parse_mentions("@everyone, @bob: hi @bob!"), &["everyone", "bob"]);
+    assert_eq!(parse_mentions("email@example.com @ @@humans"), &["@humans"]);
+}
@@ -12,22 +12,29 @@ extern crate tokio_openssl;
 
 use common::Packet;
 use futures::{Future, Stream};
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
 use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
 use openssl::rand;
+use openssl::sign::Signer;
 use openssl::ssl::{SslMethod, SslAcceptorBuilder};
 use rusqlite::{Connection as SqlConnection, Row as SqlRow};
+use rusqlite::types::ToSql;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::env;
-use std::fs::File;
-use std::io::{Read, Write, BufReader, BufWriter};
+use std::fs::{self, File};
+use std::hash::Hash;
+use std::io::{Read, Seek, SeekFrom, Write, BufReader, BufWriter};
+use std::mem;
 use std::net::{Ipv4Addr, IpAddr, SocketAddr};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::time::{Duration, Instant};
 use chrono::Utc;
 use tokio_core::net::{TcpListener, TcpStream};
-use tokio_core::reactor::{Core, Handle};
+use tokio_core::reactor::{Core, Handle, Interval};
 use tokio_io::io;
 use tokio_openssl::{SslAcceptorExt, SslStream};
 
@@ -42,23 +49,122 @@ macro_rules! attempt_or {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum AccountDeletion {
+    Anonymize,
+    Delete
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Registration {
+    Open,
+    InviteOnly,
+    Closed
+}
+
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
 struct Config {
+    // Becomes the owner while nobody owns the server. After that, owners are kept in the database.
     owner_id: usize,
+    registration: Registration,
+    // What happens to the messages of people deleting their account
+    account_deletion: AccountDeletion,
 
     limit_connections_per_ip: u32,
     limit_requests_cheap_per_10_seconds: u8,
     limit_requests_expensive_per_5_minutes: u8,
 
+    limit_attachment_max: usize,
     limit_channel_name_max: usize,
     limit_channel_name_min: usize,
+    limit_channel_topic_max: usize,
     limit_group_amount_max: usize,
     limit_group_name_max: usize,
     limit_group_name_min: usize,
     limit_message_max: usize,
     limit_message_min: usize,
     limit_user_name_max: usize,
-    limit_user_name_min: usize
+    limit_user_name_min: usize,
+
+    limit_profile_bio_max: usize,
+    limit_profile_display_name_max: usize,
+    limit_profile_pronouns_max: usize,
+    limit_profile_timezone_max: usize,
+
+    // Failed logins allowed before locking out the IP/account. 0 disables the lockout.
+    login_attempts_max: u32,
+    // Lockout in seconds, doubled for every failure past the limit.
+    login_lockout_base: u64,
+    login_lockout_max: u64,
+
+    password_min_length: usize,
+    // How many of lowercase, uppercase, digits and symbols a password has to contain
+    password_min_classes: u8,
+
+    // Private messages to offline users are kept until they log in.
+    // Age is in seconds, size in bytes per recipient.
+    // A single sender can only fill part of it, so they can't lock everyone else out.
+    pm_queue_max_age: u64,
+    pm_queue_max_size: usize,
+    pm_queue_max_size_per_sender: usize,
+
+    // Time in seconds a token is valid for without being used. None never expires them.
+    token_lifetime: Option<u64>,
+
+    // Used for channels that don't set their own retention. None keeps messages forever.
+    retention_max_age: Option<u64>,
+    retention_max_count: Option<usize>
+}
+
+// Also fills in anything missing from older config files
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            owner_id: 1,
+            registration: Registration::Open,
+            account_deletion: AccountDeletion::Anonymize,
+
+            limit_connections_per_ip: 128,
+            limit_requests_cheap_per_10_seconds: 7,
+            limit_requests_expensive_per_5_minutes: 2,
+
+            limit_attachment_max: 8 * 1024 * 1024,
+            limit_channel_name_max: 32,
+            limit_channel_name_min: 1,
+            limit_channel_topic_max: 256,
+            limit_group_amount_max: 128,
+            limit_group_name_max: 32,
+            limit_group_name_min: 1,
+            limit_message_max: 1024,
+            limit_message_min: 1,
+            limit_user_name_max: 32,
+            limit_user_name_min: 1,
+
+            limit_profile_bio_max: 256,
+            limit_profile_display_name_max: 32,
+            limit_profile_pronouns_max: 32,
+            limit_profile_timezone_max: 64,
+
+            login_attempts_max: 5,
+            login_lockout_base: 30,
+            login_lockout_max: 60*60,
+
+            password_min_length: 8,
+            password_min_classes: 2,
+
+            pm_queue_max_age: 60*60*24*14,
+            pm_queue_max_size: 64*1024,
+            pm_queue_max_size_per_sender: 16*1024,
+
+            token_lifetime: Some(60*60*24*30),
+
+            retention_max_age: None,
+            retention_max_count: None
+        }
+    }
 }
 
 fn main() {
@@ -69,11 +175,39 @@ fn main() {
         eprintln!("Just guessing here ¯\\_(ツ)_/¯");
         return;
     });
+    db.execute("CREATE TABLE IF NOT EXISTS attachments (
+                    author      INTEGER NOT NULL,
+                    hash        TEXT NOT NULL,
+                    id          INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+                    message     INTEGER,
+                    name        TEXT NOT NULL,
+                    size        INTEGER NOT NULL,
+                    timestamp   INTEGER NOT NULL
+                )", &[])
+        .expect("SQLite table creation failed");
     db.execute("CREATE TABLE IF NOT EXISTS channels (
                     id          INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
                     name        TEXT NOT NULL
                 )", &[])
         .expect("SQLite table creation failed");
+    add_column(&db, "channels", "retention_max_age", "INTEGER");
+    add_column(&db, "channels", "retention_max_count", "INTEGER");
+    add_column(&db, "channels", "topic", "TEXT");
+    add_column(&db, "channels", "category", "TEXT");
+    if add_column(&db, "channels", "pos", "INTEGER NOT NULL DEFAULT 0") {
+        db.execute("UPDATE channels SET pos = (SELECT COUNT(*) FROM channels AS other WHERE other.id <= channels.id)", &[])
+            .expect("SQLite table migration failed");
+    }
+    add_column(&db, "channels", "slow_mode", "INTEGER NOT NULL DEFAULT 0");
+    add_column(&db, "channels", "encrypted", "INTEGER NOT NULL DEFAULT 0");
+    db.execute("CREATE TABLE IF NOT EXISTS channel_keys (
+                    author      INTEGER NOT NULL,
+                    channel     INTEGER NOT NULL,
+                    data        BLOB NOT NULL,
+                    id          INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+                    recipient   INTEGER NOT NULL
+                )", &[])
+        .expect("SQLite table creation failed");
     db.execute("CREATE TABLE IF NOT EXISTS groups (
                     allow   INTEGER NOT NULL,
                     deny    INTEGER NOT NULL,
@@ -85,6 +219,15 @@ fn main() {
         .expect("SQLite table creation failed");
     db.execute("INSERT OR IGNORE INTO groups VALUES (3, 0, 1, '@humans', 0, 1)", &[]).unwrap();
     db.execute("INSERT OR IGNORE INTO groups VALUES (3, 0, 2, '@bots',   0, 1)", &[]).unwrap();
+    db.execute("CREATE TABLE IF NOT EXISTS invites (
+                    author      INTEGER NOT NULL,
+                    code        TEXT NOT NULL UNIQUE,
+                    expires     INTEGER,
+                    id          INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+                    max_uses    INTEGER,
+                    uses        INTEGER NOT NULL DEFAULT 0
+                )", &[])
+        .expect("SQLite table creation failed");
     db.execute("CREATE TABLE IF NOT EXISTS messages (
                     author      INTEGER NOT NULL,
                     channel     INTEGER NOT NULL,
@@ -94,6 +237,54 @@ fn main() {
                     timestamp_edit  INTEGER
                 )", &[])
         .expect("SQLite table creation failed");
+    let fts_exists: i64 = db.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'messages_fts'",
+        &[],
+        |row| row.get(0)
+    ).unwrap();
+    db.execute("CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts4(content=\"messages\", text)", &[])
+        .expect("SQLite table creation failed");
+    // Messages in encrypted channels are just noise to the server, so they aren't indexed.
+    // Recreated every time so older databases get the current version.
+    db.execute_batch("
+        DROP TRIGGER IF EXISTS messages_fts_before_update;
+        DROP TRIGGER IF EXISTS messages_fts_before_delete;
+        DROP TRIGGER IF EXISTS messages_fts_after_update;
+        DROP TRIGGER IF EXISTS messages_fts_after_insert;
+        CREATE TRIGGER messages_fts_before_update BEFORE UPDATE ON messages
+        WHEN NOT EXISTS (SELECT 1 FROM channels WHERE id = old.channel AND encrypted = 1) BEGIN
+            DELETE FROM messages_fts WHERE docid = old.id;
+        END;
+        CREATE TRIGGER messages_fts_before_delete BEFORE DELETE ON messages
+        WHEN NOT EXISTS (SELECT 1 FROM channels WHERE id = old.channel AND encrypted = 1) BEGIN
+            DELETE FROM messages_fts WHERE docid = old.id;
+        END;
+        CREATE TRIGGER messages_fts_after_update AFTER UPDATE ON messages
+        WHEN NOT EXISTS (SELECT 1 FROM channels WHERE id = new.channel AND encrypted = 1) BEGIN
+            INSERT INTO messages_fts (docid, text) VALUES (new.id, new.text);
+        END;
+        CREATE TRIGGER messages_fts_after_insert AFTER INSERT ON messages
+        WHEN NOT EXISTS (SELECT 1 FROM channels WHERE id = new.channel AND encrypted = 1) BEGIN
+            INSERT INTO messages_fts (docid, text) VALUES (new.id, new.text);
+        END;
+    ").expect("SQLite trigger creation failed");
+    if fts_exists == 0 {
+        // Index messages that were sent before search existed
+        db.execute("INSERT INTO messages_fts (messages_fts) VALUES ('rebuild')", &[])
+            .expect("SQLite search index creation failed");
+    }
+    db.execute("CREATE TABLE IF NOT EXISTS mentions (
+                    message     INTEGER NOT NULL,
+                    user        INTEGER NOT NULL,
+                    UNIQUE(message, user)
+                )", &[])
+        .expect("SQLite table creation failed");
+    db.execute("CREATE TABLE IF NOT EXISTS one_time_prekeys (
+                    id          INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+                    key         BLOB NOT NULL,
+                    user        INTEGER NOT NULL
+                )", &[])
+        .expect("SQLite table creation failed");
     db.execute("CREATE TABLE IF NOT EXISTS overrides (
                     allow       INTEGER NOT NULL,
                     channel     INTEGER NOT NULL,
@@ -101,6 +292,42 @@ fn main() {
                     [group]     INTEGER NOT NULL
                 )", &[])
         .expect("SQLite table creation failed");
+    db.execute("CREATE TABLE IF NOT EXISTS pins (
+                    channel     INTEGER NOT NULL,
+                    message     INTEGER NOT NULL UNIQUE,
+                    timestamp   INTEGER NOT NULL
+                )", &[])
+        .expect("SQLite table creation failed");
+    db.execute("CREATE TABLE IF NOT EXISTS pm_queue (
+                    author      INTEGER NOT NULL,
+                    id          INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+                    recipient   INTEGER NOT NULL,
+                    text        BLOB NOT NULL,
+                    timestamp   INTEGER NOT NULL
+                )", &[])
+        .expect("SQLite table creation failed");
+    db.execute("CREATE TABLE IF NOT EXISTS prekeys (
+                    identity            BLOB NOT NULL,
+                    identity_signature  BLOB NOT NULL,
+                    signed              BLOB NOT NULL,
+                    signed_signature    BLOB NOT NULL,
+                    user                INTEGER NOT NULL PRIMARY KEY
+                )", &[])
+        .expect("SQLite table creation failed");
+    db.execute("CREATE TABLE IF NOT EXISTS reactions (
+                    author      INTEGER NOT NULL,
+                    emoji       TEXT NOT NULL,
+                    message     INTEGER NOT NULL,
+                    UNIQUE(author, emoji, message)
+                )", &[])
+        .expect("SQLite table creation failed");
+    db.execute("CREATE TABLE IF NOT EXISTS reads (
+                    channel     INTEGER NOT NULL,
+                    message     INTEGER NOT NULL,
+                    user        INTEGER NOT NULL,
+                    UNIQUE(channel, user)
+                )", &[])
+        .expect("SQLite table creation failed");
     db.execute("CREATE TABLE IF NOT EXISTS users (
                     ban         INTEGER NOT NULL DEFAULT 0,
                     bot         INTEGER NOT NULL,
@@ -112,6 +339,45 @@ fn main() {
                     token       TEXT NOT NULL
                 )", &[])
         .expect("SQLite table creation failed");
+    db.execute("CREATE TABLE IF NOT EXISTS tokens (
+                    id          INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+                    label       TEXT NOT NULL,
+                    last_ip     TEXT NOT NULL,
+                    timestamp   INTEGER NOT NULL,
+                    timestamp_used  INTEGER NOT NULL,
+                    token       TEXT NOT NULL,
+                    user        INTEGER NOT NULL
+                )", &[])
+        .expect("SQLite table creation failed");
+    // Tokens used to be a single column in users
+    db.execute("INSERT INTO tokens (label, last_ip, timestamp, timestamp_used, token, user)
+                SELECT 'Unknown device', last_ip, 0, 0, token, id FROM users WHERE token != ''", &[])
+        .expect("SQLite table migration failed");
+    db.execute("UPDATE users SET token = '' WHERE token != ''", &[])
+        .expect("SQLite table migration failed");
+    add_column(&db, "tokens", "hashed", "INTEGER NOT NULL DEFAULT 0");
+    add_column(&db, "users", "owner", "INTEGER NOT NULL DEFAULT 0");
+    add_column(&db, "users", "status_dnd", "INTEGER NOT NULL DEFAULT 0");
+    add_column(&db, "users", "status_text", "TEXT");
+    add_column(&db, "users", "profile_bio", "TEXT");
+    add_column(&db, "users", "profile_display_name", "TEXT");
+    add_column(&db, "users", "profile_pronouns", "TEXT");
+    add_column(&db, "users", "profile_timezone", "TEXT");
+    add_column(&db, "users", "public_key", "BLOB");
+
+    if let Err(err) = fs::create_dir_all(ATTACHMENT_DIR) {
+        eprintln!("Failed to create attachment directory: {}", err);
+        return;
+    }
+    // Uploads that were still in progress when the server stopped
+    if let Ok(entries) = fs::read_dir(ATTACHMENT_DIR) {
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            if path.extension().map(|ext| ext == "part").unwrap_or(false) {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
 
     let mut args = env::args();
     args.next();
@@ -193,30 +459,27 @@ fn main() {
             if is_invalid!(limit_user_name_min, limit_user_name_max, common::LIMIT_USER_NAME)
                 || is_invalid!(limit_channel_name_min, limit_channel_name_max, common::LIMIT_CHANNEL_NAME)
                 || is_invalid!(limit_group_name_min, limit_group_name_max, common::LIMIT_GROUP_NAME)
+                || config.limit_channel_topic_max > common::LIMIT_CHANNEL_TOPIC
                 || config.limit_group_amount_max > common::LIMIT_GROUP_AMOUNT
+                || config.limit_attachment_max == 0
+                || config.limit_attachment_max > common::LIMIT_ATTACHMENT
+                || config.login_lockout_base > config.login_lockout_max
+                || config.password_min_classes > 4
+                || config.pm_queue_max_size_per_sender > config.pm_queue_max_size
+                || config.limit_profile_bio_max > common::LIMIT_PROFILE_BIO
+                || config.limit_profile_display_name_max > common::LIMIT_PROFILE_FIELD
+                || config.limit_profile_pronouns_max > common::LIMIT_PROFILE_FIELD
+                || config.limit_profile_timezone_max > common::LIMIT_PROFILE_FIELD
+                || config.retention_max_age.map_or(false, |age| age > common::LIMIT_RETENTION_AGE)
+                || config.token_lifetime.map_or(false, |lifetime| lifetime == 0 || lifetime > TOKEN_LIFETIME_MAX)
+                || config.retention_max_count.map_or(false, |count| count > common::LIMIT_RETENTION_COUNT)
                 || is_invalid!(limit_message_min, limit_message_max, common::LIMIT_MESSAGE) {
 
                 eprintln!("Your config is exceeding a hard limit");
                 return;
             }
         } else {
-            config = Config {
-                owner_id: 1,
-
-                limit_connections_per_ip: 128,
-                limit_requests_cheap_per_10_seconds: 7,
-                limit_requests_expensive_per_5_minutes: 2,
-
-                limit_channel_name_max: 32,
-                limit_channel_name_min: 1,
-                limit_group_amount_max: 128,
-                limit_group_name_max: 32,
-                limit_group_name_min: 1,
-                limit_message_max: 1024,
-                limit_message_min: 1,
-                limit_user_name_max: 32,
-                limit_user_name_min: 1
-            };
+            config = Config::default();
 
             match File::create(path) {
                 Ok(mut file) => if let Err(err) = serde_json::to_writer_pretty(&mut file, &config) {
@@ -227,6 +490,28 @@ fn main() {
         }
     }
 
+    seed_owner(&config, &db);
+
+    let token_key = attempt_or!(load_token_key(Path::new("token-key.bin")), {
+        eprintln!("Failed to load or create the token key.");
+        eprintln!("Do I have the required permission to write in this directory?");
+        return;
+    });
+    {
+        // Tokens used to be stored verbatim
+        let tokens: Vec<(i64, String)> = {
+            let mut stmt = db.prepare("SELECT id, token FROM tokens WHERE hashed = 0").unwrap();
+            let rows = stmt.query_map(&[], |row| (row.get(0), row.get(1))).unwrap();
+            rows.map(|row| row.unwrap()).collect()
+        };
+        for (id, token) in tokens {
+            db.execute(
+                "UPDATE tokens SET hashed = 1, token = ? WHERE id = ?",
+                &[&hash_token(&token_key, &token), &id]
+            ).unwrap();
+        }
+    }
+
     let mut core = Core::new().expect("Could not start tokio core!");
     let handle = core.handle();
     let listener = attempt_or!(TcpListener::bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port), &handle), {
@@ -242,8 +527,44 @@ fn main() {
     let handle   = Rc::new(handle);
     let ips      = Rc::new(RefCell::new(HashMap::new()));
     let sessions = Rc::new(RefCell::new(HashMap::new()));
+    let throttle = Rc::new(RefCell::new(Throttle::new()));
+    let token_key = Rc::new(token_key);
     let users    = Rc::new(RefCell::new(HashMap::new()));
 
+    {
+        let config   = Rc::clone(&config);
+        let db       = Rc::clone(&db);
+        let sessions = Rc::clone(&sessions);
+
+        let interval = Interval::new(Duration::from_secs(RETENTION_INTERVAL), &handle)
+            .expect("Could not start retention timer!");
+        handle.spawn(interval.map_err(|_| ()).for_each(move |_| {
+            prune_messages(&config, &db, &mut sessions.borrow_mut());
+            prune_pms(&config, &db);
+            prune_attachments(&db);
+            Ok(())
+        }));
+    }
+    {
+        let db       = Rc::clone(&db);
+        let sessions = Rc::clone(&sessions);
+        let users    = Rc::clone(&users);
+
+        let interval = Interval::new(Duration::from_secs(PRESENCE_INTERVAL), &handle)
+            .expect("Could not start presence timer!");
+        handle.spawn(interval.map_err(|_| ()).for_each(move |_| {
+            // Catches people going idle, as well as sessions that were kicked
+            let ids: Vec<_> = users.borrow().iter()
+                .filter(|&(_, user)| user.presence != common::Presence::Offline)
+                .map(|(id, _)| *id)
+                .collect();
+            for id in ids {
+                update_presence(&db, &mut sessions.borrow_mut(), &mut users.borrow_mut(), id);
+            }
+            Ok(())
+        }));
+    }
+
     println!("I'm alive!");
 
     let server = listener.incoming().for_each(|(conn, addr)| {
@@ -255,6 +576,8 @@ fn main() {
         let handle_clone   = Rc::clone(&handle);
         let ips_clone      = Rc::clone(&ips);
         let sessions_clone = Rc::clone(&sessions);
+        let throttle_clone = Rc::clone(&throttle);
+        let token_key_clone = Rc::clone(&token_key);
         let users_clone    = Rc::clone(&users);
 
         let accept = ssl.accept_async(conn).map_err(|_| ()).and_then(move |conn| {
@@ -276,6 +599,10 @@ fn main() {
 
             sessions_clone.borrow_mut().insert(my_conn_id, Session {
                 id: None,
+                last_active: Instant::now(),
+                export: None,
+                token: None,
+                upload: None,
                 writer: writer
             });
 
@@ -288,6 +615,8 @@ fn main() {
                 ips_clone,
                 reader,
                 sessions_clone,
+                throttle_clone,
+                token_key_clone,
                 users_clone
             );
 
@@ -302,13 +631,98 @@ fn main() {
 
 pub const TOKEN_CHARS: &[u8; 62] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
 pub const RESERVED_ROLES: usize = 2;
+// Owners bypass every permission check
+pub const OWNER_PERMISSIONS: u16 = std::u16::MAX;
+pub const ATTACHMENT_DIR: &str = "attachments";
+// How long an upload may wait to be sent with a message before it's thrown away
+pub const ATTACHMENT_UNUSED_MAX_AGE: i64 = 60*60*24;
+pub const RETENTION_INTERVAL: u64 = 60;
+// How many messages may be pruned each interval, so a lowered limit doesn't stall the server
+pub const RETENTION_BATCH: usize = 1024;
+pub const TOKENS_PER_USER: usize = 64;
+pub const TOKEN_KEY_SIZE: usize = 32;
+pub const TOKEN_LIFETIME_MAX: u64 = 60*60*24*365*10;
+pub const INVITE_LENGTH: usize = 12;
+// How many channel keys by one author are kept for each recipient.
+// Someone who misses more rotations than this can't read the oldest messages.
+pub const CHANNEL_KEYS_PER_AUTHOR: usize = 16;
+pub const INVITES_PER_USER: usize = 64;
+// Clients are asked for new prekeys once this few one-time prekeys are left
+pub const PREKEYS_LOW: usize = 10;
+pub const IDLE_TIMEOUT: u64 = 60*5;
+pub const PRESENCE_INTERVAL: u64 = 30;
+
+fn add_column(db: &SqlConnection, table: &str, column: &str, definition: &str) -> bool {
+    let exists = {
+        let mut stmt = db.prepare(&format!("PRAGMA table_info({})", table)).unwrap();
+        let mut rows = stmt.query_map(&[], |row| row.get::<_, String>(1)).unwrap();
+        rows.any(|name| name.unwrap() == column)
+    };
+    if !exists {
+        db.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), &[])
+            .expect("SQLite table migration failed");
+    }
+    !exists
+}
+
+// Members of encrypted channels share their keys with each other, so they need to know who's in it
+fn broadcast_members(db: &SqlConnection, sessions: &mut HashMap<usize, Session>) {
+    let channels: Vec<_> = {
+        let mut stmt = db.prepare_cached("SELECT * FROM channels WHERE encrypted = 1").unwrap();
+        let rows = stmt.query_map(&[], |row| get_channel_by_fields(db, row)).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    };
+
+    for channel in channels {
+        write_broadcast(
+            Some(&channel.overrides),
+            db,
+            &Packet::ChannelMembersReceive(common::ChannelMembersReceive {
+                channel: channel.id,
+                members: get_members(db, &channel)
+            }),
+            None,
+            sessions
+        );
+    }
+}
+fn broadcast_moved_channels(
+    db: &SqlConnection,
+    before: &HashMap<usize, usize>,
+    except: Option<usize>,
+    sessions: &mut HashMap<usize, Session>
+) {
+    let channels: Vec<_> = {
+        let mut stmt = db.prepare_cached("SELECT * FROM channels").unwrap();
+        let rows = stmt.query_map(&[], |row| get_channel_by_fields(db, row)).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    };
 
+    for channel in channels {
+        if Some(channel.id) == except || before.get(&channel.id) == Some(&channel.pos) {
+            continue;
+        }
+        write_broadcast(
+            None,
+            db,
+            &Packet::ChannelReceive(common::ChannelReceive {
+                inner: channel
+            }),
+            None,
+            sessions
+        );
+    }
+}
 fn calculate_permissions(
         db: &SqlConnection,
         bot: bool,
+        owner: bool,
         groups: &[usize],
-        chan_overrides: Option<&HashMap<usize, (u8, u8)>>
-    ) -> u8 {
+        chan_overrides: Option<&HashMap<usize, (u16, u16)>>
+    ) -> u16 {
+    if owner {
+        return OWNER_PERMISSIONS;
+    }
     let mut query = String::with_capacity(48+3+1+14);
     query.push_str("SELECT allow, deny FROM groups WHERE id IN (");
     query.push_str(if bot { "2" } else { "1" });
@@ -338,16 +752,16 @@ fn calculate_permissions(
 fn calculate_permissions_by_user(
         db: &SqlConnection,
         id: usize,
-        chan_overrides: Option<&HashMap<usize, (u8, u8)>>
-    ) -> Option<u8> {
-    let mut stmt = db.prepare_cached("SELECT bot, groups FROM users WHERE id = ?").unwrap();
+        chan_overrides: Option<&HashMap<usize, (u16, u16)>>
+    ) -> Option<u16> {
+    let mut stmt = db.prepare_cached("SELECT bot, groups, owner FROM users WHERE id = ?").unwrap();
     let mut rows = stmt.query(&[&(id as i64)]).unwrap();
 
     if let Some(row) = rows.next() {
         let row = row.unwrap();
         // Yes I realize I could pass row.get(0) directly.
         // However, what about SQL injections?
-        Some(calculate_permissions(db, row.get(0), &get_list(&row.get::<_, String>(1)), chan_overrides))
+        Some(calculate_permissions(db, row.get(0), row.get(2), &get_list(&row.get::<_, String>(1)), chan_overrides))
     } else {
         None
     }
@@ -383,6 +797,95 @@ fn check_rate_limits(config: &Config, expensive: bool, session: &mut UserSession
     }
     None
 }
+fn create_token(db: &SqlConnection, token_key: &[u8], user: usize, label: &str, ip: &IpAddr)
+        -> Result<(usize, String), openssl::error::ErrorStack> {
+    let token = gen_token()?;
+    let timestamp = Utc::now().timestamp();
+
+    db.execute(
+        "INSERT INTO tokens (hashed, label, last_ip, timestamp, timestamp_used, token, user)
+        VALUES (1, ?, ?, ?, ?, ?, ?)",
+        &[&label, &ip.to_string(), &timestamp, &timestamp, &hash_token(token_key, &token), &(user as i64)]
+    ).unwrap();
+    let id = db.last_insert_rowid() as usize;
+
+    // Forget the least recently used devices
+    db.execute(
+        "DELETE FROM tokens WHERE user = ? AND id NOT IN
+        (SELECT id FROM tokens WHERE user = ? ORDER BY timestamp_used DESC, id DESC LIMIT ?)",
+        &[&(user as i64), &(user as i64), &(TOKENS_PER_USER as i64)]
+    ).unwrap();
+
+    Ok((id, token))
+}
+// Deletes attachments, along with their files once no other attachment uses them
+fn delete_attachments(db: &SqlConnection, filter: &str, params: &[&ToSql]) {
+    let hashes: Vec<String> = {
+        let mut stmt = db.prepare(&format!("SELECT DISTINCT hash FROM attachments WHERE {}", filter)).unwrap();
+        let rows = stmt.query_map(params, |row| row.get(0)).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    };
+    db.execute(&format!("DELETE FROM attachments WHERE {}", filter), params).unwrap();
+
+    for hash in hashes {
+        let used: i64 = db.query_row(
+            "SELECT COUNT(*) FROM attachments WHERE hash = ?",
+            &[&hash],
+            |row| row.get(0)
+        ).unwrap();
+        if used == 0 {
+            if let Err(err) = fs::remove_file(Path::new(ATTACHMENT_DIR).join(&hash)) {
+                eprintln!("Failed to delete attachment file {}: {}", hash, err);
+            }
+        }
+    }
+}
+fn delete_messages(db: &SqlConnection, ids: &[usize]) {
+    let list = from_list(ids);
+
+    let mut filter = String::with_capacity(12 + list.len() + 1);
+    filter.push_str("message IN (");
+    filter.push_str(&list);
+    filter.push(')');
+    delete_attachments(db, &filter, &[]);
+
+    for table in &["mentions", "pins", "reactions"] {
+        let mut query = String::with_capacity(12 + table.len() + 22 + list.len() + 1);
+        query.push_str("DELETE FROM ");
+        query.push_str(table);
+        query.push_str(" WHERE message IN (");
+        query.push_str(&list);
+        query.push(')');
+
+        db.execute(&query, &[]).unwrap();
+    }
+
+    let mut query = String::with_capacity(34 + list.len() + 1);
+    query.push_str("DELETE FROM messages WHERE id IN (");
+    query.push_str(&list);
+    query.push(')');
+
+    db.execute(&query, &[]).unwrap();
+}
+// Returns the id, when the token was issued and when it was last used
+fn find_token(db: &SqlConnection, token_key: &[u8], user: usize, token: &str) -> Option<(usize, i64, i64)> {
+    let hash = hash_token(token_key, token);
+
+    let mut stmt = db.prepare_cached("SELECT id, timestamp, timestamp_used, token FROM tokens WHERE user = ?").unwrap();
+    let mut rows = stmt.query(&[&(user as i64)]).unwrap();
+
+    let mut found = None;
+    while let Some(row) = rows.next() {
+        let row = row.unwrap();
+        let row_token: String = row.get(3);
+
+        // Don't bail out early, so the timing doesn't reveal anything either
+        if row_token.len() == hash.len() && memcmp::eq(row_token.as_bytes(), hash.as_bytes()) {
+            found = Some((row.get::<_, i64>(0) as usize, row.get(1), row.get(2)));
+        }
+    }
+    found
+}
 fn from_list(input: &[usize]) -> String {
     input.iter().fold(String::new(), |mut acc, item| {
         if !acc.is_empty() { acc.push(','); }
@@ -399,6 +902,12 @@ fn gen_token() -> Result<String, openssl::error::ErrorStack> {
 
     Ok(unsafe { String::from_utf8_unchecked(token) })
 }
+fn get_attachments(db: &SqlConnection, message: usize) -> Vec<usize> {
+    let mut stmt = db.prepare_cached("SELECT id FROM attachments WHERE message = ? ORDER BY id").unwrap();
+    let rows = stmt.query_map(&[&(message as i64)], |row| row.get::<_, i64>(0) as usize).unwrap();
+
+    rows.map(|row| row.unwrap()).collect()
+}
 fn get_channel(db: &SqlConnection, id: usize) -> Option<common::Channel> {
     let mut stmt = db.prepare_cached("SELECT * FROM channels WHERE id = ?").unwrap();
     let mut rows = stmt.query(&[&(id as i64)]).unwrap();
@@ -409,6 +918,11 @@ fn get_channel(db: &SqlConnection, id: usize) -> Option<common::Channel> {
         None
     }
 }
+fn get_channel_positions(db: &SqlConnection) -> HashMap<usize, usize> {
+    let mut stmt = db.prepare_cached("SELECT id, pos FROM channels").unwrap();
+    let rows = stmt.query_map(&[], |row| (row.get::<_, i64>(0) as usize, row.get::<_, i64>(1) as usize)).unwrap();
+    rows.map(|row| row.unwrap()).collect()
+}
 fn get_channel_by_fields(db: &SqlConnection, row: &SqlRow) -> common::Channel {
     let id = row.get::<_, i64>(0);
 
@@ -423,9 +937,16 @@ fn get_channel_by_fields(db: &SqlConnection, row: &SqlRow) -> common::Channel {
     }
 
     common::Channel {
+        category: row.get(5),
+        encrypted: row.get(8),
         id: id as usize,
         name: row.get(1),
-        overrides: overrides
+        overrides: overrides,
+        pos: row.get::<_, i64>(6) as usize,
+        retention_max_age: row.get::<_, Option<i64>>(2).map(|age| age as u64),
+        retention_max_count: row.get::<_, Option<i64>>(3).map(|count| count as usize),
+        slow_mode: row.get::<_, i64>(7) as u64,
+        topic: row.get(4)
     }
 }
 fn get_group(db: &SqlConnection, id: usize) -> Option<common::Group> {
@@ -448,33 +969,202 @@ fn get_group_by_fields(row: &SqlRow) -> common::Group {
         unassignable: row.get(5)
     }
 }
+fn get_invite_by_fields(row: &SqlRow) -> common::Invite {
+    common::Invite {
+        author: row.get::<_, i64>(0) as usize,
+        code: row.get(1),
+        expires: row.get(2),
+        id: row.get::<_, i64>(3) as usize,
+        max_uses: row.get::<_, Option<i64>>(4).map(|uses| uses as usize),
+        uses: row.get::<_, i64>(5) as usize
+    }
+}
 fn get_list(input: &str) -> Vec<usize> {
     input.split(',')
         .filter(|s| !s.is_empty())
         .map(|s| s.parse().expect("The database is broken. Congratz. You made me crash."))
         .collect()
 }
+fn get_members(db: &SqlConnection, channel: &common::Channel) -> Vec<usize> {
+    let mut stmt = db.prepare_cached("SELECT id, bot, groups, owner FROM users WHERE ban = 0").unwrap();
+    let mut rows = stmt.query(&[]).unwrap();
+    let mut members = Vec::new();
+
+    while let Some(row) = rows.next() {
+        let row = row.unwrap();
+        let id = row.get::<_, i64>(0) as usize;
+        let bot: bool = row.get(1);
+        let groups = get_list(&row.get::<_, String>(2));
+        let owner: bool = row.get(3);
+
+        if has_perm(
+            calculate_permissions(db, bot, owner, &groups, Some(&channel.overrides)),
+            common::PERM_READ
+        ) {
+            members.push(id);
+        }
+    }
+
+    members
+}
+fn get_mentions(db: &SqlConnection, channel: &common::Channel, msg: &common::Message) -> Vec<usize> {
+    let text = String::from_utf8_lossy(&msg.text);
+    let names = common::parse_mentions(&text);
+    if names.is_empty() {
+        return Vec::new();
+    }
+
+    // Mentioning more than one user at once needs a permission
+    let everyone = has_perm(
+        calculate_permissions_by_user(db, msg.author, Some(&channel.overrides)).unwrap_or(0),
+        common::PERM_MENTION_EVERYONE
+    );
+    let mut groups = Vec::new();
+    if everyone {
+        let mut stmt = db.prepare_cached("SELECT id, name FROM groups").unwrap();
+        let rows = stmt.query_map(&[], |row| (row.get::<_, i64>(0) as usize, row.get::<_, String>(1))).unwrap();
+
+        for row in rows {
+            let (id, name) = row.unwrap();
+            if names.contains(&&*name) {
+                groups.push(id);
+            }
+        }
+    }
+    let everyone = everyone && names.contains(&common::MENTION_EVERYONE);
+
+    // Only everyone and groups need to look at every user, names are looked up directly
+    let candidates: Vec<(usize, bool, Vec<usize>, bool)> = if everyone || !groups.is_empty() {
+        let mut stmt = db.prepare_cached("SELECT id, bot, groups, name, owner FROM users WHERE ban = 0").unwrap();
+        let rows = stmt.query_map(&[], |row| {
+            let bot: bool = row.get(1);
+            let user_groups = get_list(&row.get::<_, String>(2));
+            let name: String = row.get(3);
+
+            let mentioned = everyone
+                || names.iter().any(|mention| mention.to_lowercase() == name.to_lowercase())
+                || groups.iter().any(|group| {
+                    *group == if bot { 2 } else { 1 } || user_groups.contains(group)
+                });
+            if mentioned {
+                Some((row.get::<_, i64>(0) as usize, bot, user_groups, row.get(4)))
+            } else {
+                None
+            }
+        }).unwrap();
+        rows.filter_map(|row| row.unwrap()).collect()
+    } else {
+        let mut names = names;
+        names.sort_unstable();
+        names.dedup();
+
+        let mut stmt = db.prepare_cached("SELECT id, bot, groups, owner FROM users WHERE ban = 0 AND name = ?").unwrap();
+        let mut candidates = Vec::new();
+        for name in names {
+            let rows = stmt.query_map(&[&name], |row| (
+                row.get::<_, i64>(0) as usize,
+                row.get(1),
+                get_list(&row.get::<_, String>(2)),
+                row.get(3)
+            )).unwrap();
+            candidates.extend(rows.map(|row| row.unwrap()));
+        }
+        candidates
+    };
+
+    let mut mentions = Vec::new();
+    for (id, bot, user_groups, owner) in candidates {
+        if id != msg.author && !mentions.contains(&id) && has_perm(
+            calculate_permissions(db, bot, owner, &user_groups, Some(&channel.overrides)),
+            common::PERM_READ
+        ) {
+            mentions.push(id);
+        }
+    }
+
+    mentions
+}
 fn get_message(db: &SqlConnection, id: usize) -> Option<common::Message> {
     let mut stmt = db.prepare_cached("SELECT * FROM messages WHERE id = ?")
         .unwrap();
     let mut rows = stmt.query(&[&(id as i64)]).unwrap();
     if let Some(row) = rows.next() {
         let row = row.unwrap();
-        Some(get_message_by_fields(&row))
+        let mut messages = vec![get_message_by_fields(db, &row)];
+        load_reactions(db, &mut messages);
+        messages.pop()
     } else {
         None
     }
 }
-fn get_message_by_fields(row: &SqlRow) -> common::Message {
+fn get_message_by_fields(db: &SqlConnection, row: &SqlRow) -> common::Message {
+    let id = row.get::<_, i64>(2);
+
     common::Message {
+        attachments: get_attachments(db, id as usize),
         author: row.get::<_, i64>(0) as usize,
         channel: row.get::<_, i64>(1) as usize,
-        id: row.get::<_, i64>(2) as usize,
+        id: id as usize,
+        reactions: HashMap::new(),
         text: row.get(3),
         timestamp: row.get(4),
         timestamp_edit: row.get(5)
     }
 }
+fn get_presence(sessions: &HashMap<usize, Session>, user: usize) -> common::Presence {
+    let timeout = Duration::from_secs(IDLE_TIMEOUT);
+    let mut presence = common::Presence::Offline;
+
+    for session in sessions.values().filter(|session| session.id == Some(user)) {
+        if session.last_active.elapsed() < timeout {
+            return common::Presence::Online;
+        }
+        presence = common::Presence::Idle;
+    }
+    presence
+}
+fn get_presence_packet(db: &SqlConnection, user: usize, presence: common::Presence) -> Option<Packet> {
+    let mut stmt = db.prepare_cached("SELECT status_dnd, status_text FROM users WHERE id = ?").unwrap();
+    let mut rows = stmt.query(&[&(user as i64)]).unwrap();
+
+    rows.next().map(|row| {
+        let row = row.unwrap();
+        Packet::PresenceReceive(common::PresenceReceive {
+            dnd: row.get(0),
+            id: user,
+            presence: presence,
+            status: row.get(1)
+        })
+    })
+}
+fn get_unread_packet(db: &SqlConnection, channel: usize, user: usize) -> Packet {
+    let last_read: Option<i64> = db.query_row(
+        "SELECT message FROM reads WHERE channel = ? AND user = ?",
+        &[&(channel as i64), &(user as i64)],
+        |row| row.get(0)
+    ).ok();
+    let after = last_read.unwrap_or(0);
+
+    // Your own messages don't count as unread
+    let unread: i64 = db.query_row(
+        "SELECT COUNT(*) FROM messages WHERE channel = ? AND id > ? AND author != ?",
+        &[&(channel as i64), &after, &(user as i64)],
+        |row| row.get(0)
+    ).unwrap();
+    let mentions: i64 = db.query_row(
+        "SELECT COUNT(*) FROM mentions JOIN messages ON mentions.message = messages.id
+        WHERE messages.channel = ? AND messages.id > ? AND mentions.user = ?",
+        &[&(channel as i64), &after, &(user as i64)],
+        |row| row.get(0)
+    ).unwrap();
+
+    Packet::UnreadReceive(common::UnreadReceive {
+        channel: channel,
+        last_read: last_read.map(|id| id as usize),
+        mentions: mentions as usize,
+        unread: unread as usize
+    })
+}
 fn get_user(db: &SqlConnection, id: usize) -> Option<common::User> {
     let mut stmt = db.prepare_cached("SELECT * FROM users WHERE id = ?").unwrap();
     let mut rows = stmt.query(&[&(id as i64)]).unwrap();
@@ -491,13 +1181,33 @@ fn get_user_by_fields(row: &SqlRow) -> common::User {
         bot: row.get(1),
         groups: get_list(&row.get::<_, String>(2)),
         id: row.get::<_, i64>(3) as usize,
-        name: row.get(5)
+        name: row.get(5),
+        owner: row.get(8),
+        profile: common::Profile {
+            bio: row.get(11),
+            display_name: row.get(12),
+            pronouns: row.get(13),
+            timezone: row.get(14)
+        },
+        public_key: row.get(15)
     }
 }
-fn has_perm(config: &Config, user: usize, bitmask: u8, perm: u8) -> bool {
-    config.owner_id == user || bitmask & perm == perm
+fn hash_token(token_key: &[u8], token: &str) -> String {
+    let pkey = PKey::hmac(token_key).unwrap();
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey).unwrap();
+    signer.update(token.as_bytes()).unwrap();
+
+    let hash = signer.finish().unwrap();
+    let mut hash_str = String::with_capacity(64);
+    for byte in &hash {
+        hash_str.push_str(&format!("{:02x}", byte));
+    }
+    hash_str
+}
+fn has_perm(bitmask: u16, perm: u16) -> bool {
+    bitmask & perm == perm
 }
-fn insert_channel_overrides(db: &SqlConnection, channel: usize, overrides: &HashMap<usize, (u8, u8)>) {
+fn insert_channel_overrides(db: &SqlConnection, channel: usize, overrides: &HashMap<usize, (u16, u16)>) {
     db.execute("DELETE FROM overrides WHERE channel = ?", &[&(channel as i64)]).unwrap();
 
     let mut stmt_exists = db.prepare_cached("SELECT COUNT(*) FROM groups WHERE id = ?") .unwrap();
@@ -514,6 +1224,267 @@ fn insert_channel_overrides(db: &SqlConnection, channel: usize, overrides: &Hash
         }
     }
 }
+fn is_locked<K: Eq + Hash>(attempts: &HashMap<K, LoginAttempts>, key: &K) -> bool {
+    attempts.get(key)
+        .and_then(|attempts| attempts.locked_until)
+        .map(|until| until > Instant::now())
+        .unwrap_or(false)
+}
+fn is_owner(db: &SqlConnection, user: usize) -> bool {
+    let mut stmt = db.prepare_cached("SELECT owner FROM users WHERE id = ?").unwrap();
+    let mut rows = stmt.query(&[&(user as i64)]).unwrap();
+
+    match rows.next() {
+        Some(row) => row.unwrap().get(0),
+        None => false
+    }
+}
+fn is_valid_prekeys(prekeys: &common::PrekeyPublish) -> bool {
+    let inner = &prekeys.inner;
+    !inner.identity.is_empty() && !inner.signed.is_empty()
+        && inner.identity.len() <= common::LIMIT_PREKEY
+        && inner.identity_signature.len() <= common::LIMIT_PREKEY
+        && inner.signed.len() <= common::LIMIT_PREKEY
+        && inner.signed_signature.len() <= common::LIMIT_PREKEY
+        && prekeys.one_time.len() <= common::LIMIT_PREKEY_AMOUNT
+        && prekeys.one_time.iter().all(|key| !key.is_empty() && key.len() <= common::LIMIT_PREKEY)
+}
+fn is_valid_reaction(emoji: &str) -> bool {
+    if emoji.is_empty() || emoji.len() > common::LIMIT_REACTION {
+        return false;
+    }
+    let chars: Vec<_> = emoji.chars().collect();
+    chars.iter().enumerate().all(|(i, &c)| {
+        if c.is_whitespace() || c.is_control() {
+            return false;
+        }
+        if !c.is_alphanumeric() {
+            return true;
+        }
+        // Keycap emoji are a digit followed by U+20E3, sometimes with U+FE0F in between
+        let next = chars[i+1..].iter().find(|&&c| c != '\u{fe0f}');
+        c.is_digit(10) && next == Some(&'\u{20e3}')
+    })
+}
+fn is_weak_password(config: &Config, password: &str) -> bool {
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_numeric()),
+        password.chars().any(|c| !c.is_alphanumeric())
+    ];
+
+    password.chars().count() < config.password_min_length
+        || classes.iter().filter(|class| **class).count() < config.password_min_classes as usize
+}
+// Fills in the reactions of a whole list of messages at once
+fn load_reactions(db: &SqlConnection, messages: &mut [common::Message]) {
+    if messages.is_empty() {
+        return;
+    }
+    let ids: Vec<i64> = messages.iter().map(|msg| msg.id as i64).collect();
+    let params: Vec<&ToSql> = ids.iter().map(|id| id as &ToSql).collect();
+    let placeholders = vec!["?"; ids.len()].join(", ");
+
+    let mut stmt = db.prepare(&format!(
+        "SELECT message, emoji, COUNT(*) FROM reactions WHERE message IN ({}) GROUP BY message, emoji",
+        placeholders
+    )).unwrap();
+    let mut rows = stmt.query(&params).unwrap();
+
+    while let Some(row) = rows.next() {
+        let row = row.unwrap();
+        let message = row.get::<_, i64>(0) as usize;
+        if let Some(msg) = messages.iter_mut().find(|msg| msg.id == message) {
+            msg.reactions.insert(row.get(1), row.get::<_, i64>(2) as usize);
+        }
+    }
+}
+fn load_token_key(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut key = Vec::with_capacity(TOKEN_KEY_SIZE);
+    if path.exists() {
+        File::open(path)?.read_to_end(&mut key)?;
+        if key.len() != TOKEN_KEY_SIZE {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "token key has the wrong size"));
+        }
+    } else {
+        key.resize(TOKEN_KEY_SIZE, 0);
+        rand::rand_bytes(&mut key)?;
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            // Anyone who can read this can forge tokens
+            options.mode(0o600);
+        }
+        options.open(path)?.write_all(&key)?;
+    }
+    Ok(key)
+}
+fn needs_prekeys(db: &SqlConnection, user: usize) -> bool {
+    let (published, one_time): (i64, i64) = db.query_row(
+        "SELECT (SELECT COUNT(*) FROM prekeys WHERE user = ?), (SELECT COUNT(*) FROM one_time_prekeys WHERE user = ?)",
+        &[&(user as i64), &(user as i64)],
+        |row| (row.get(0), row.get(1))
+    ).unwrap();
+    published == 0 || one_time as usize <= PREKEYS_LOW
+}
+fn prune_messages(config: &Config, db: &SqlConnection, sessions: &mut HashMap<usize, Session>) {
+    let channels: Vec<_> = {
+        let mut stmt = db.prepare_cached("SELECT * FROM channels").unwrap();
+        let rows = stmt.query_map(&[], |row| get_channel_by_fields(db, row)).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    };
+    let now = Utc::now().timestamp();
+    // Whatever doesn't fit in this batch is picked up by the next interval
+    let mut budget = RETENTION_BATCH;
+
+    for channel in channels {
+        if budget == 0 {
+            break;
+        }
+        // 0 means the channel explicitly opted out of the server default
+        let max_age = channel.retention_max_age.or(config.retention_max_age).unwrap_or(0);
+        let max_count = channel.retention_max_count.or(config.retention_max_count).unwrap_or(0);
+        // Values stored before the limits existed could be anything
+        let max_age = std::cmp::min(max_age, common::LIMIT_RETENTION_AGE);
+        let max_count = std::cmp::min(max_count, common::LIMIT_RETENTION_COUNT);
+
+        let mut ids = Vec::new();
+        if max_age != 0 {
+            let cutoff = now.saturating_sub(max_age as i64);
+            let mut stmt = db.prepare_cached(
+                "SELECT id FROM messages WHERE channel = ? AND timestamp < ? ORDER BY id LIMIT ?"
+            ).unwrap();
+            let rows = stmt.query_map(
+                &[&(channel.id as i64), &cutoff, &(budget as i64)],
+                |row| row.get::<_, i64>(0) as usize
+            ).unwrap();
+            ids.extend(rows.map(|row| row.unwrap()));
+        }
+        if max_count != 0 {
+            let mut stmt = db.prepare_cached(
+                "SELECT id FROM messages WHERE channel = ? ORDER BY timestamp DESC, id DESC LIMIT ? OFFSET ?"
+            ).unwrap();
+            let rows = stmt.query_map(
+                &[&(channel.id as i64), &(budget as i64), &(max_count as i64)],
+                |row| row.get::<_, i64>(0) as usize
+            ).unwrap();
+            ids.extend(rows.map(|row| row.unwrap()));
+        }
+        if ids.is_empty() {
+            continue;
+        }
+        ids.sort_unstable();
+        ids.dedup();
+        ids.truncate(budget);
+        budget -= ids.len();
+
+        delete_messages(db, &ids);
+
+        for id in ids {
+            let packet = Packet::MessageDeleteReceive(common::MessageDeleteReceive {
+                id: id
+            });
+            write_broadcast(
+                Some(&channel.overrides),
+                db,
+                &packet,
+                None,
+                sessions
+            );
+        }
+    }
+}
+fn prune_attachments(db: &SqlConnection) {
+    delete_attachments(
+        db,
+        "message IS NULL AND timestamp < ?",
+        &[&(Utc::now().timestamp() - ATTACHMENT_UNUSED_MAX_AGE)]
+    );
+}
+// Oldest timestamp a queued private message may have
+fn pm_queue_cutoff(config: &Config) -> i64 {
+    let max_age = std::cmp::min(config.pm_queue_max_age, std::i64::MAX as u64) as i64;
+    Utc::now().timestamp().saturating_sub(max_age)
+}
+fn prune_pms(config: &Config, db: &SqlConnection) {
+    db.execute(
+        "DELETE FROM pm_queue WHERE timestamp < ?",
+        &[&pm_queue_cutoff(config)]
+    ).unwrap();
+}
+fn record_login_failure<K: Eq + Hash>(config: &Config, attempts: &mut HashMap<K, LoginAttempts>, key: K) {
+    if config.login_attempts_max == 0 {
+        return;
+    }
+    let now = Instant::now();
+    let forget = Duration::from_secs(config.login_lockout_max);
+
+    // Old failures are forgiven, which also keeps the map from growing forever
+    attempts.retain(|_, attempts| now.duration_since(attempts.last) < forget);
+
+    let attempts = attempts.entry(key).or_insert_with(|| LoginAttempts {
+        failures: 0,
+        last: now,
+        locked_until: None
+    });
+    attempts.failures += 1;
+    attempts.last = now;
+
+    if attempts.failures >= config.login_attempts_max {
+        let doublings = (attempts.failures - config.login_attempts_max).min(16);
+        let lockout = config.login_lockout_base.saturating_mul(1 << doublings).min(config.login_lockout_max);
+        attempts.locked_until = Some(now + Duration::from_secs(lockout));
+    }
+}
+fn seed_owner(config: &Config, db: &SqlConnection) {
+    db.execute(
+        "UPDATE users SET owner = 1 WHERE id = ? AND NOT EXISTS (SELECT * FROM users WHERE owner = 1)",
+        &[&(config.owner_id as i64)]
+    ).unwrap();
+}
+fn store_prekeys(db: &SqlConnection, user: usize, prekeys: &common::PrekeyPublish) {
+    db.execute(
+        "REPLACE INTO prekeys (identity, identity_signature, signed, signed_signature, user)
+        VALUES (?, ?, ?, ?, ?)",
+        &[
+            &prekeys.inner.identity,
+            &prekeys.inner.identity_signature,
+            &prekeys.inner.signed,
+            &prekeys.inner.signed_signature,
+            &(user as i64)
+        ]
+    ).unwrap();
+    for key in &prekeys.one_time {
+        db.execute(
+            "INSERT INTO one_time_prekeys (key, user) VALUES (?, ?)",
+            &[key, &(user as i64)]
+        ).unwrap();
+    }
+    // Only keep the newest ones, the rest will never be claimed anyway
+    db.execute(
+        "DELETE FROM one_time_prekeys WHERE user = ? AND id NOT IN (
+            SELECT id FROM one_time_prekeys WHERE user = ? ORDER BY id DESC LIMIT ?
+        )",
+        &[&(user as i64), &(user as i64), &(common::LIMIT_PREKEY_AMOUNT as i64)]
+    ).unwrap();
+}
+fn to_fts_query(input: &str) -> String {
+    // Quote every word so the user can't inject FTS syntax (and crash the query).
+    input.split_whitespace().fold(String::new(), |mut acc, word| {
+        let word: String = word.chars().filter(|c| *c != '"').collect();
+        if !word.is_empty() {
+            if !acc.is_empty() { acc.push(' '); }
+            acc.push('"');
+            acc.push_str(&word);
+            acc.push('"');
+        }
+        acc
+    })
+}
 fn write<T: std::io::Write>(writer: &mut T, packet: Packet) -> bool {
     attempt_or!(common::write(writer, &packet), {
         eprintln!("Failed to send reply");
@@ -521,9 +1492,56 @@ fn write<T: std::io::Write>(writer: &mut T, packet: Packet) -> bool {
     });
     true
 }
+fn update_mentions(
+    db: &SqlConnection,
+    channel: &common::Channel,
+    msg: &common::Message,
+    sessions: &mut HashMap<usize, Session>
+) {
+    let old: Vec<usize> = {
+        let mut stmt = db.prepare_cached("SELECT user FROM mentions WHERE message = ?").unwrap();
+        let rows = stmt.query_map(&[&(msg.id as i64)], |row| row.get::<_, i64>(0) as usize).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    };
+    let new = get_mentions(db, channel, msg);
+
+    db.execute("DELETE FROM mentions WHERE message = ?", &[&(msg.id as i64)]).unwrap();
+
+    let packet = Packet::MentionReceive(common::MentionReceive {
+        inner: msg.clone()
+    });
+    for user in new {
+        db.execute(
+            "INSERT INTO mentions (message, user) VALUES (?, ?)",
+            &[&(msg.id as i64), &(user as i64)]
+        ).unwrap();
+
+        // Don't notify again when a message that already mentioned someone is edited
+        if !old.contains(&user) {
+            write_broadcast(None, db, &packet, Some(user), sessions);
+        }
+    }
+}
+fn update_presence(
+    db: &SqlConnection,
+    sessions: &mut HashMap<usize, Session>,
+    users: &mut HashMap<usize, UserSession>,
+    user: usize
+) {
+    let presence = get_presence(sessions, user);
+    {
+        let user = users.entry(user).or_insert_with(UserSession::new);
+        if user.presence == presence {
+            return;
+        }
+        user.presence = presence;
+    }
+    if let Some(packet) = get_presence_packet(db, user, presence) {
+        write_broadcast(None, db, &packet, None, sessions);
+    }
+}
 fn write_broadcast(
-    channel_overrides: Option<&HashMap<usize, (u8, u8)>>,
-    config: &Config,
+    channel_overrides: Option<&HashMap<usize, (u16, u16)>>,
     db: &SqlConnection,
     packet: &Packet,
     recipient: Option<usize>,
@@ -540,8 +1558,6 @@ fn write_broadcast(
             // Check if the user really has permission to read this message.
             if let Some(overrides) = channel_overrides {
                 if !has_perm(
-                    config,
-                    id,
                     calculate_permissions_by_user(db, id, Some(overrides)).unwrap(),
                     common::PERM_READ
                 ) {
@@ -577,30 +1593,87 @@ fn write_broadcast(
     });
 }
 
+#[derive(Serialize)]
+struct Export {
+    groups: Vec<common::Group>,
+    last_ip: String,
+    messages: Vec<ExportMessage>,
+    user: common::User
+}
+#[derive(Serialize)]
+struct ExportMessage {
+    channel: usize,
+    id: usize,
+    text: String,
+    timestamp: i64,
+    timestamp_edit: Option<i64>
+}
+struct LoginAttempts {
+    failures: u32,
+    last: Instant,
+    locked_until: Option<Instant>
+}
+struct Throttle {
+    accounts: HashMap<usize, LoginAttempts>,
+    ips: HashMap<IpAddr, LoginAttempts>
+}
 struct UserSession {
     packet_time_cheap: Instant,
     packet_time_expensive: Instant,
     packets_cheap: usize,
     packets_expensive: usize,
+    presence: common::Presence,
+    // Channel ID -> last message sent there, for slow mode
+    slow_mode: HashMap<usize, Instant>
 }
 struct Session {
+    // Serialized once, then sent one chunk at a time as the client asks for them
+    export: Option<Vec<u8>>,
     id: Option<usize>,
+    last_active: Instant,
+    token: Option<usize>,
+    upload: Option<Upload>,
     writer: BufWriter<tokio_io::io::WriteHalf<SslStream<TcpStream>>>
 }
+// Written to disk as it arrives, so the declared size is never held in memory
+struct Upload {
+    file: File,
+    hasher: openssl::sha::Sha256,
+    name: String,
+    path: PathBuf,
+    received: usize,
+    size: usize
+}
+impl Drop for Upload {
+    fn drop(&mut self) {
+        // Already gone if the upload was completed and moved into place
+        let _ = fs::remove_file(&self.path);
+    }
+}
+impl Throttle {
+    fn new() -> Throttle {
+        Throttle {
+            accounts: HashMap::new(),
+            ips: HashMap::new()
+        }
+    }
+}
 impl UserSession {
     fn new() -> UserSession {
         UserSession {
             packet_time_cheap: Instant::now(),
             packet_time_expensive: Instant::now(),
             packets_cheap: 0,
-            packets_expensive: 0
+            packets_expensive: 0,
+            presence: common::Presence::Offline,
+            slow_mode: HashMap::new()
         }
     }
 }
 
 enum Reply {
     // Send the message to all clients (optionally restricted to channel)
-    Broadcast(Option<HashMap<usize, (u8, u8)>>, Packet),
+    Broadcast(Option<HashMap<usize, (u16, u16)>>, Packet),
     // Send the message to all clients with ID
     Private(usize, Packet),
     // Send initial packets like channels, groups, et.c
@@ -620,12 +1693,17 @@ fn handle_client(
         ips:      Rc<RefCell<HashMap<IpAddr, u32>>>,
         reader:   BufReader<tokio_io::io::ReadHalf<SslStream<TcpStream>>>,
         sessions: Rc<RefCell<HashMap<usize, Session>>>,
+        throttle: Rc<RefCell<Throttle>>,
+        token_key: Rc<Vec<u8>>,
         users:    Rc<RefCell<HashMap<usize, UserSession>>>
     ) {
     macro_rules! close {
         () => {
-            sessions.borrow_mut().remove(&conn_id);
+            let id = sessions.borrow_mut().remove(&conn_id).and_then(|session| session.id);
             *ips.borrow_mut().get_mut(&ip).unwrap() -= 1;
+            if let Some(id) = id {
+                update_presence(&db, &mut sessions.borrow_mut(), &mut users.borrow_mut(), id);
+            }
             return Ok(());
         }
     }
@@ -658,6 +1736,14 @@ fn handle_client(
                         }
                     };
 
+                    let was_idle = {
+                        let mut sessions = sessions.borrow_mut();
+                        let session = sessions.get_mut(&conn_id).unwrap();
+                        let idle = session.last_active.elapsed() >= Duration::from_secs(IDLE_TIMEOUT);
+                        session.last_active = Instant::now();
+                        idle
+                    };
+
                     let mut send_init = false;
                     let mut reply = handle_packet(
                         &config,
@@ -666,6 +1752,8 @@ fn handle_client(
                         &ip,
                         packet,
                         &mut sessions.borrow_mut(),
+                        &mut throttle.borrow_mut(),
+                        &token_key,
                         &mut users.borrow_mut()
                     );
 
@@ -678,7 +1766,6 @@ fn handle_client(
                         Reply::Broadcast(channel, packet) => {
                             write_broadcast(
                                 channel.as_ref(),
-                                &config,
                                 &db,
                                 &packet,
                                 None,
@@ -688,7 +1775,6 @@ fn handle_client(
                         Reply::Private(recipient, packet) => {
                             write_broadcast(
                                 None,
-                                &config,
                                 &db,
                                 &packet,
                                 Some(recipient),
@@ -708,7 +1794,10 @@ fn handle_client(
 
                     if send_init {
                         let mut sessions = sessions.borrow_mut();
-                        let writer = &mut sessions.get_mut(&conn_id).unwrap().writer;
+                        let session = sessions.get_mut(&conn_id).unwrap();
+                        let id = session.id;
+                        let writer = &mut session.writer;
+                        let mut encrypted = Vec::new();
                         {
                             let mut stmt = db.prepare_cached("SELECT * FROM groups").unwrap();
                             let mut rows = stmt.query(&[]).unwrap();
@@ -727,10 +1816,26 @@ fn handle_client(
 
                             while let Some(row) = rows.next() {
                                 let row = row.unwrap();
-
+                                let channel = get_channel_by_fields(&db, &row);
+
+                                let unread = id.and_then(|id| if has_perm(
+                                    calculate_permissions_by_user(&db, id, Some(&channel.overrides)).unwrap(),
+                                    common::PERM_READ
+                                ) {
+                                    Some(get_unread_packet(&db, channel.id, id))
+                                } else {
+                                    None
+                                });
+
+                                if unread.is_some() && channel.encrypted {
+                                    encrypted.push(channel.clone());
+                                }
                                 write(writer, Packet::ChannelReceive(common::ChannelReceive {
-                                    inner: get_channel_by_fields(&db, &row),
+                                    inner: channel,
                                 }));
+                                if let Some(packet) = unread {
+                                    write(writer, packet);
+                                }
                             }
                         } {
                             let mut stmt = db.prepare_cached("SELECT * FROM users").unwrap();
@@ -744,6 +1849,67 @@ fn handle_client(
                                 }));
                             }
                         }
+                        // After the users, since the keys can't be verified without theirs
+                        for channel in encrypted {
+                            write(writer, Packet::ChannelMembersReceive(common::ChannelMembersReceive {
+                                channel: channel.id,
+                                members: get_members(&db, &channel)
+                            }));
+
+                            let mut stmt = db.prepare_cached(
+                                "SELECT author, data FROM channel_keys WHERE channel = ? AND recipient = ? ORDER BY id"
+                            ).unwrap();
+                            let mut rows = stmt.query(&[&(channel.id as i64), &(id.unwrap() as i64)]).unwrap();
+
+                            while let Some(row) = rows.next() {
+                                let row = row.unwrap();
+
+                                write(writer, Packet::ChannelKeyReceive(common::ChannelKeyReceive {
+                                    author: row.get::<_, i64>(0) as usize,
+                                    channel: channel.id,
+                                    data: row.get(1)
+                                }));
+                            }
+                        }
+                        for (id, user) in users.borrow().iter() {
+                            if user.presence == common::Presence::Offline {
+                                continue;
+                            }
+                            if let Some(packet) = get_presence_packet(&db, *id, user.presence) {
+                                write(writer, packet);
+                            }
+                        }
+                        if let Some(id) = id {
+                            let mut stmt = db.prepare_cached(
+                                "SELECT author, id, text, timestamp FROM pm_queue WHERE recipient = ? AND timestamp >= ? ORDER BY id"
+                            ).unwrap();
+                            let mut rows = stmt.query(
+                                &[&(id as i64), &pm_queue_cutoff(&config)]
+                            ).unwrap();
+
+                            while let Some(row) = rows.next() {
+                                let row = row.unwrap();
+
+                                write(writer, Packet::PMReceive(common::PMReceive {
+                                    author: row.get::<_, i64>(0) as usize,
+                                    id: Some(row.get::<_, i64>(1) as usize),
+                                    text: row.get(2),
+                                    timestamp: row.get(3)
+                                }));
+                            }
+                            // Ask for prekeys before others run out of ways to start a session
+                            if needs_prekeys(&db, id) {
+                                write(writer, Packet::PrekeyStatusReceive(common::PrekeyStatusReceive {
+                                    published: false
+                                }));
+                            }
+                        }
+                    }
+                    if send_init || was_idle {
+                        let id = sessions.borrow().get(&conn_id).and_then(|session| session.id);
+                        if let Some(id) = id {
+                            update_presence(&db, &mut sessions.borrow_mut(), &mut users.borrow_mut(), id);
+                        }
                     }
 
                     handle_client(
@@ -755,6 +1921,8 @@ fn handle_client(
                         ips,
                         reader,
                         sessions,
+                        throttle,
+                        token_key,
                         users
                     );
 
@@ -775,6 +1943,8 @@ fn handle_packet(
     ip: &IpAddr,
     packet: Packet,
     sessions: &mut HashMap<usize, Session>,
+    throttle: &mut Throttle,
+    token_key: &[u8],
     users: &mut HashMap<usize, UserSession>
 ) -> Reply {
     macro_rules! get_id {
@@ -798,7 +1968,7 @@ fn handle_packet(
                 let user = &mut users.entry($id).or_insert_with(UserSession::new);
                 if let Some(left) = check_rate_limits(config, $expensive, user) {
                     let session = &mut sessions.get_mut(&conn_id).unwrap();
-                    write(&mut session.writer, Packet::RateLimited(left));
+                    write(&mut session.writer, Packet::RateLimited(left, None));
                     stop = true;
                 }
             }
@@ -817,6 +1987,343 @@ fn handle_packet(
     }
 
     match packet {
+        Packet::AccountDelete(event) => {
+            let id = get_id!();
+            rate_limit!(id, expensive);
+
+            // Owners have to step down first, so a server is never left without one
+            if is_owner(db, id) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+            if is_locked(&throttle.accounts, &id) {
+                return Reply::Reply(Packet::Err(common::ERR_LOGIN_LOCKED));
+            }
+
+            let password: String = db.query_row(
+                "SELECT password FROM users WHERE id = ?",
+                &[&(id as i64)],
+                |row| row.get(0)
+            ).unwrap();
+            let valid = attempt_or!(bcrypt::verify(&event.password, &password), {
+                eprintln!("Failed to verify password");
+                return Reply::Close;
+            });
+            if !valid {
+                record_login_failure(config, &mut throttle.accounts, id);
+                return Reply::Reply(Packet::Err(common::ERR_LOGIN_INVALID));
+            }
+
+            // Log out every device first, broadcasts check the permissions of each recipient
+            sessions.retain(|i, s| *i == conn_id || s.id != Some(id));
+            {
+                let session = sessions.get_mut(&conn_id).unwrap();
+                write(&mut session.writer, Packet::UserDeleteReceive(common::UserDeleteReceive {
+                    id: id
+                }));
+                session.id = None;
+                session.token = None;
+            }
+            throttle.accounts.remove(&id);
+            users.remove(&id);
+
+            match config.account_deletion {
+                AccountDeletion::Anonymize => {
+                    db.execute("UPDATE messages SET author = 0 WHERE author = ?", &[&(id as i64)]).unwrap();
+                    db.execute(
+                        "UPDATE attachments SET author = 0 WHERE author = ? AND message IS NOT NULL",
+                        &[&(id as i64)]
+                    ).unwrap();
+                },
+                AccountDeletion::Delete => {
+                    let messages: Vec<(usize, usize)> = {
+                        let mut stmt = db.prepare_cached("SELECT id, channel FROM messages WHERE author = ?").unwrap();
+                        let rows = stmt.query_map(&[&(id as i64)], |row| {
+                            (row.get::<_, i64>(0) as usize, row.get::<_, i64>(1) as usize)
+                        }).unwrap();
+                        rows.map(|row| row.unwrap()).collect()
+                    };
+                    let ids: Vec<usize> = messages.iter().map(|&(message, _)| message).collect();
+
+                    delete_messages(db, &ids);
+
+                    let mut channels = HashMap::new();
+                    for (message, channel) in messages {
+                        let overrides = channels.entry(channel)
+                            .or_insert_with(|| get_channel(db, channel).map(|channel| channel.overrides));
+                        let packet = Packet::MessageDeleteReceive(common::MessageDeleteReceive {
+                            id: message
+                        });
+                        if let Some(ref overrides) = *overrides {
+                            write_broadcast(Some(overrides), db, &packet, None, sessions);
+                        }
+                    }
+                }
+            }
+
+            delete_attachments(db, "author = ?", &[&(id as i64)]);
+            db.execute("DELETE FROM channel_keys WHERE author = ? OR recipient = ?", &[&(id as i64), &(id as i64)]).unwrap();
+            db.execute("DELETE FROM invites WHERE author = ?", &[&(id as i64)]).unwrap();
+            db.execute("DELETE FROM mentions WHERE user = ?", &[&(id as i64)]).unwrap();
+            db.execute("DELETE FROM one_time_prekeys WHERE user = ?", &[&(id as i64)]).unwrap();
+            db.execute("DELETE FROM pm_queue WHERE author = ? OR recipient = ?", &[&(id as i64), &(id as i64)]).unwrap();
+            db.execute("DELETE FROM prekeys WHERE user = ?", &[&(id as i64)]).unwrap();
+            db.execute("DELETE FROM reactions WHERE author = ?", &[&(id as i64)]).unwrap();
+            db.execute("DELETE FROM reads WHERE user = ?", &[&(id as i64)]).unwrap();
+            db.execute("DELETE FROM tokens WHERE user = ?", &[&(id as i64)]).unwrap();
+            db.execute("DELETE FROM users WHERE id = ?", &[&(id as i64)]).unwrap();
+            broadcast_members(db, sessions);
+
+            write_broadcast(
+                None,
+                db,
+                &Packet::UserDeleteReceive(common::UserDeleteReceive {
+                    id: id
+                }),
+                None,
+                sessions
+            );
+
+            Reply::Close
+        },
+        Packet::AccountExport(event) => {
+            let id = get_id!();
+
+            if event.offset != 0 {
+                let session = sessions.get_mut(&conn_id).unwrap();
+                let (chunk, size) = {
+                    let data = unwrap_or_err!(session.export.as_ref(), common::ERR_ATTACHMENT_INVALID_CHUNK);
+                    if event.offset >= data.len() {
+                        return Reply::Reply(Packet::Err(common::ERR_ATTACHMENT_INVALID_CHUNK));
+                    }
+                    let end = std::cmp::min(event.offset + common::LIMIT_ATTACHMENT_CHUNK, data.len());
+                    (data[event.offset..end].to_vec(), data.len())
+                };
+                if event.offset + chunk.len() >= size {
+                    session.export = None;
+                }
+                return Reply::Reply(Packet::AccountExportReceive(common::AccountExportReceive {
+                    data: chunk,
+                    offset: event.offset,
+                    size: size
+                }));
+            }
+            rate_limit!(id, expensive);
+
+            let user = get_user(db, id).unwrap();
+            let last_ip: String = db.query_row(
+                "SELECT last_ip FROM users WHERE id = ?",
+                &[&(id as i64)],
+                |row| row.get(0)
+            ).unwrap();
+            let messages = {
+                let mut stmt = db.prepare_cached(
+                    "SELECT channel, id, text, timestamp, timestamp_edit FROM messages WHERE author = ? ORDER BY id"
+                ).unwrap();
+                let rows = stmt.query_map(&[&(id as i64)], |row| ExportMessage {
+                    channel: row.get::<_, i64>(0) as usize,
+                    id: row.get::<_, i64>(1) as usize,
+                    text: String::from_utf8_lossy(&row.get::<_, Vec<u8>>(2)).into_owned(),
+                    timestamp: row.get(3),
+                    timestamp_edit: row.get(4)
+                }).unwrap();
+                rows.map(|row| row.unwrap()).collect()
+            };
+
+            let export = Export {
+                groups: user.groups.iter().filter_map(|group| get_group(db, *group)).collect(),
+                last_ip: last_ip,
+                messages: messages,
+                user: user
+            };
+            let data = attempt_or!(serde_json::to_vec_pretty(&export), {
+                eprintln!("Failed to serialize account export");
+                return Reply::Close;
+            });
+
+            let chunk = data[..std::cmp::min(common::LIMIT_ATTACHMENT_CHUNK, data.len())].to_vec();
+            let size = data.len();
+            sessions.get_mut(&conn_id).unwrap().export = if chunk.len() < size { Some(data) } else { None };
+
+            Reply::Reply(Packet::AccountExportReceive(common::AccountExportReceive {
+                data: chunk,
+                offset: 0,
+                size: size
+            }))
+        },
+        Packet::AckChannel(event) => {
+            // Not rate limited, clients send this on their own while reading and it only moves the user's own marker
+            let id = get_id!();
+
+            let channel = unwrap_or_err!(get_channel(db, event.channel), common::ERR_UNKNOWN_CHANNEL);
+
+            if !has_perm(
+                calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
+                common::PERM_READ
+            ) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+            match get_message(db, event.message) {
+                Some(ref msg) if msg.channel == channel.id => (),
+                _ => return Reply::Reply(Packet::Err(common::ERR_UNKNOWN_MESSAGE))
+            }
+
+            db.execute(
+                "INSERT OR REPLACE INTO reads (channel, message, user) VALUES (?, ?, ?)",
+                &[&(channel.id as i64), &(event.message as i64), &(id as i64)]
+            ).unwrap();
+
+            // Let the user's other devices know as well
+            Reply::Private(id, get_unread_packet(db, channel.id, id))
+        },
+        Packet::AttachmentDownload(event) => {
+            let id = get_id!();
+            if event.offset == 0 {
+                // Only count new downloads, the rest are just the client asking for the next chunk
+                rate_limit!(id, cheap);
+            }
+
+            let (author, hash, message, size) = {
+                let mut stmt = db.prepare_cached(
+                    "SELECT author, hash, message, size FROM attachments WHERE id = ?"
+                ).unwrap();
+                let mut rows = stmt.query(&[&(event.id as i64)]).unwrap();
+
+                let row = match rows.next() {
+                    Some(row) => row.unwrap(),
+                    None => return Reply::Reply(Packet::Err(common::ERR_UNKNOWN_ATTACHMENT))
+                };
+                (
+                    row.get::<_, i64>(0) as usize,
+                    row.get::<_, String>(1),
+                    row.get::<_, Option<i64>>(2).map(|message| message as usize),
+                    row.get::<_, i64>(3) as usize
+                )
+            };
+
+            if author != id {
+                // Attachments are only visible to people who can read the message they're in.
+                let message = unwrap_or_err!(message, common::ERR_MISSING_PERMISSION);
+                let msg = unwrap_or_err!(get_message(db, message), common::ERR_UNKNOWN_ATTACHMENT);
+                let channel = get_channel(db, msg.channel).unwrap();
+
+                if !has_perm(
+                    calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
+                    common::PERM_READ
+                ) {
+                    return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+                }
+            }
+
+            if event.offset >= size {
+                return Reply::Reply(Packet::Err(common::ERR_ATTACHMENT_INVALID_CHUNK));
+            }
+
+            // Sent one chunk at a time, so a big file can't fill up the connection
+            let mut data = vec![0; std::cmp::min(common::LIMIT_ATTACHMENT_CHUNK, size - event.offset)];
+            let path = Path::new(ATTACHMENT_DIR).join(&hash);
+            let read = File::open(path).and_then(|mut file| {
+                file.seek(SeekFrom::Start(event.offset as u64))?;
+                file.read_exact(&mut data)
+            });
+            if let Err(err) = read {
+                eprintln!("Failed to read attachment #{}: {}", event.id, err);
+                return Reply::Reply(Packet::Err(common::ERR_UNKNOWN_ATTACHMENT));
+            }
+
+            Reply::Reply(Packet::AttachmentChunkReceive(common::AttachmentChunkReceive {
+                data: data,
+                id: event.id,
+                offset: event.offset,
+                size: size
+            }))
+        },
+        Packet::AttachmentUpload(event) => {
+            let id = get_id!();
+            if event.offset == 0 {
+                // Only count new uploads, or no file would ever make it through
+                rate_limit!(id, cheap);
+            }
+
+            if event.size == 0
+                || event.size > config.limit_attachment_max
+                || event.data.is_empty()
+                || event.data.len() > common::LIMIT_ATTACHMENT_CHUNK
+                || event.name.is_empty()
+                || event.name.len() > common::LIMIT_ATTACHMENT_NAME {
+                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+            }
+
+            let session = sessions.get_mut(&conn_id).unwrap();
+
+            if event.offset == 0 {
+                // Dropping the previous upload, if any, removes its file before this one reuses the name
+                session.upload = None;
+
+                let path = Path::new(ATTACHMENT_DIR).join(format!("{}.part", conn_id));
+                let file = match File::create(&path) {
+                    Ok(file) => file,
+                    Err(err) => {
+                        eprintln!("Failed to create attachment: {}", err);
+                        return Reply::Reply(Packet::Err(common::ERR_ATTACHMENT_INVALID_CHUNK));
+                    }
+                };
+                session.upload = Some(Upload {
+                    file: file,
+                    hasher: openssl::sha::Sha256::new(),
+                    name: event.name,
+                    path: path,
+                    received: 0,
+                    size: event.size
+                });
+            }
+            let mut upload = unwrap_or_err!(session.upload.take(), common::ERR_ATTACHMENT_INVALID_CHUNK);
+
+            if upload.received != event.offset
+                || upload.size != event.size
+                || event.offset + event.data.len() > upload.size {
+                return Reply::Reply(Packet::Err(common::ERR_ATTACHMENT_INVALID_CHUNK));
+            }
+            if let Err(err) = upload.file.write_all(&event.data) {
+                eprintln!("Failed to save attachment: {}", err);
+                return Reply::Reply(Packet::Err(common::ERR_ATTACHMENT_INVALID_CHUNK));
+            }
+            upload.hasher.update(&event.data);
+            upload.received += event.data.len();
+
+            if upload.received < upload.size {
+                session.upload = Some(upload);
+                return Reply::None;
+            }
+
+            let mut hash_str = String::with_capacity(64);
+            let hash = mem::replace(&mut upload.hasher, openssl::sha::Sha256::new()).finish();
+            for byte in &hash {
+                hash_str.push_str(&format!("{:02x}", byte));
+            }
+
+            // Files are stored by hash, so identical uploads share the same file
+            let path = Path::new(ATTACHMENT_DIR).join(&hash_str);
+            if !path.exists() {
+                if let Err(err) = upload.file.flush().and_then(|_| fs::rename(&upload.path, &path)) {
+                    eprintln!("Failed to save attachment: {}", err);
+                    return Reply::Reply(Packet::Err(common::ERR_ATTACHMENT_INVALID_CHUNK));
+                }
+            }
+
+            db.execute(
+                "INSERT INTO attachments (author, hash, name, size, timestamp) VALUES (?, ?, ?, ?, ?)",
+                &[&(id as i64), &hash_str, &upload.name, &(upload.size as i64), &Utc::now().timestamp()]
+            ).unwrap();
+
+            Reply::Reply(Packet::AttachmentReceive(common::AttachmentReceive {
+                inner: common::Attachment {
+                    author: id,
+                    id: db.last_insert_rowid() as usize,
+                    name: upload.name.clone(),
+                    size: upload.size
+                }
+            }))
+        },
         Packet::Close => { Reply::Close }
         Packet::ChannelCreate(channel) => {
             let id = get_id!();
@@ -828,26 +2335,53 @@ fn handle_packet(
                 return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
             }
             if !has_perm(
-                config,
-                id,
                 calculate_permissions_by_user(db, id, None).unwrap(),
                 common::PERM_MANAGE_CHANNELS
             ) {
                 return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
             }
 
+            if let Some(ref category) = channel.category {
+                if category.len() < config.limit_channel_name_min
+                    || category.len() > config.limit_channel_name_max {
+                    return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+                }
+            }
+            let max: i64 = db.query_row(
+                "SELECT COALESCE(MAX(pos), 0) FROM channels",
+                &[],
+                |row| row.get(0)
+            ).unwrap();
+
+            if channel.pos == 0 || channel.pos > max as usize + 1 {
+                return Reply::Reply(Packet::Err(common::ERR_CHANNEL_INVALID_POS));
+            }
+
+            let before = get_channel_positions(db);
             db.execute(
-                "INSERT INTO channels (name) VALUES (?)",
-                &[&channel.name]
+                "UPDATE channels SET pos = pos + 1 WHERE pos >= ?",
+                &[&(channel.pos as i64)]
+            ).unwrap();
+            db.execute(
+                "INSERT INTO channels (category, encrypted, name, pos) VALUES (?, ?, ?, ?)",
+                &[&channel.category, &channel.encrypted, &channel.name, &(channel.pos as i64)]
             ).unwrap();
             let channel_id = db.last_insert_rowid() as usize;
             insert_channel_overrides(db, channel_id, &channel.overrides);
+            broadcast_moved_channels(db, &before, Some(channel_id), sessions);
 
             Reply::Broadcast(None, Packet::ChannelReceive(common::ChannelReceive {
                 inner: common::Channel {
+                    category: channel.category,
+                    encrypted: channel.encrypted,
                     overrides:  channel.overrides,
                     id: channel_id,
-                    name: channel.name
+                    name: channel.name,
+                    pos: channel.pos,
+                    retention_max_age: None,
+                    retention_max_count: None,
+                    slow_mode: 0,
+                    topic: None
                 }
             }))
         },
@@ -858,26 +2392,128 @@ fn handle_packet(
             let channel = unwrap_or_err!(get_channel(db, event.id), common::ERR_UNKNOWN_CHANNEL);
 
             if !has_perm(
-                config,
-                id,
                 calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
                 common::PERM_MANAGE_CHANNELS
             ) {
                 return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
             }
 
+            delete_attachments(
+                db,
+                "message IN (SELECT id FROM messages WHERE channel = ?)",
+                &[&(event.id as i64)]
+            );
+            db.execute("DELETE FROM channel_keys WHERE channel = ?", &[&(event.id as i64)]).unwrap();
+            db.execute(
+                "DELETE FROM mentions WHERE message IN (SELECT id FROM messages WHERE channel = ?)",
+                &[&(event.id as i64)]
+            ).unwrap();
+            db.execute(
+                "DELETE FROM reactions WHERE message IN (SELECT id FROM messages WHERE channel = ?)",
+                &[&(event.id as i64)]
+            ).unwrap();
             db.execute("DELETE FROM messages WHERE channel = ?", &[&(event.id as i64)]).unwrap();
             db.execute("DELETE FROM overrides WHERE channel = ?", &[&(event.id as i64)]).unwrap();
+            db.execute("DELETE FROM pins WHERE channel = ?", &[&(event.id as i64)]).unwrap();
+            db.execute("DELETE FROM reads WHERE channel = ?", &[&(event.id as i64)]).unwrap();
             db.execute("DELETE FROM channels WHERE id = ?", &[&(event.id as i64)]).unwrap();
 
+            let before = get_channel_positions(db);
+            db.execute(
+                "UPDATE channels SET pos = pos - 1 WHERE pos > ?",
+                &[&(channel.pos as i64)]
+            ).unwrap();
+            broadcast_moved_channels(db, &before, None, sessions);
+
             Reply::Broadcast(None, Packet::ChannelDeleteReceive(common::ChannelDeleteReceive {
-                inner: common::Channel {
-                    id: channel.id,
-                    name: channel.name,
-                    overrides: channel.overrides
-                }
+                inner: channel
+            }))
+        },
+        Packet::ChannelKeyPublish(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            let channel = unwrap_or_err!(get_channel(db, event.channel), common::ERR_UNKNOWN_CHANNEL);
+            if !channel.encrypted {
+                return Reply::Reply(Packet::Err(common::ERR_UNKNOWN_CHANNEL));
+            }
+
+            let members = get_members(db, &channel);
+            if !members.contains(&id) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+            if event.packages.iter().any(|(recipient, data)| {
+                !members.contains(recipient) || data.is_empty() || data.len() > common::LIMIT_CHANNEL_KEY
+            }) {
+                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+            }
+
+            // Kept around so members get keys that were sent while they were offline
+            for (recipient, data) in event.packages {
+                db.execute(
+                    "INSERT INTO channel_keys (author, channel, data, recipient) VALUES (?, ?, ?, ?)",
+                    &[&(id as i64), &(channel.id as i64), &data, &(recipient as i64)]
+                ).unwrap();
+                db.execute(
+                    "DELETE FROM channel_keys WHERE author = ? AND channel = ? AND recipient = ? AND id NOT IN (
+                        SELECT id FROM channel_keys WHERE author = ? AND channel = ? AND recipient = ? ORDER BY id DESC LIMIT ?
+                    )",
+                    &[
+                        &(id as i64), &(channel.id as i64), &(recipient as i64),
+                        &(id as i64), &(channel.id as i64), &(recipient as i64),
+                        &(CHANNEL_KEYS_PER_AUTHOR as i64)
+                    ]
+                ).unwrap();
+                write_broadcast(
+                    None,
+                    db,
+                    &Packet::ChannelKeyReceive(common::ChannelKeyReceive {
+                        author: id,
+                        channel: channel.id,
+                        data: data
+                    }),
+                    Some(recipient),
+                    sessions
+                );
+            }
+
+            // The client holds back messages encrypted with the key until now
+            Reply::Reply(Packet::ChannelKeyDeliveryReceive(common::ChannelKeyDeliveryReceive {
+                channel: channel.id
             }))
         },
+        Packet::ChannelReorder(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            if !has_perm(
+                calculate_permissions_by_user(db, id, None).unwrap(),
+                common::PERM_MANAGE_CHANNELS
+            ) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+
+            let before = get_channel_positions(db);
+            let mut ids = event.ids.clone();
+            ids.sort_unstable();
+            ids.dedup();
+
+            if ids.len() != event.ids.len()
+                || ids.len() != before.len()
+                || ids.iter().any(|id| !before.contains_key(id)) {
+                return Reply::Reply(Packet::Err(common::ERR_CHANNEL_INVALID_POS));
+            }
+
+            for (i, channel) in event.ids.iter().enumerate() {
+                db.execute(
+                    "UPDATE channels SET pos = ? WHERE id = ?",
+                    &[&(i as i64 + 1), &(*channel as i64)]
+                ).unwrap();
+            }
+            broadcast_moved_channels(db, &before, None, sessions);
+
+            Reply::None
+        },
         Packet::ChannelUpdate(event) => {
             let id = get_id!();
             rate_limit!(id, cheap);
@@ -888,32 +2524,80 @@ fn handle_packet(
                 || channel.overrides.len() > config.limit_group_amount_max {
                 return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
             }
+            if let Some(ref topic) = channel.topic {
+                if topic.is_empty() || topic.len() > config.limit_channel_topic_max {
+                    return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+                }
+            }
+            if let Some(ref category) = channel.category {
+                if category.len() < config.limit_channel_name_min
+                    || category.len() > config.limit_channel_name_max {
+                    return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+                }
+            }
+            if channel.slow_mode > common::LIMIT_SLOW_MODE
+                || channel.retention_max_age.map_or(false, |age| age > common::LIMIT_RETENTION_AGE)
+                || channel.retention_max_count.map_or(false, |count| count > common::LIMIT_RETENTION_COUNT) {
+                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+            }
 
             let old = unwrap_or_err!(get_channel(db, channel.id), common::ERR_UNKNOWN_CHANNEL);
 
             if !has_perm(
-                config,
-                id,
                 calculate_permissions_by_user(db, id, Some(&old.overrides)).unwrap(),
                 common::PERM_MANAGE_CHANNELS
             ) {
                 return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
             }
 
+            let max: i64 = db.query_row(
+                "SELECT MAX(pos) FROM channels",
+                &[],
+                |row| row.get(0)
+            ).unwrap();
+
+            if channel.pos == 0 || channel.pos > max as usize {
+                return Reply::Reply(Packet::Err(common::ERR_CHANNEL_INVALID_POS));
+            }
+            let before = get_channel_positions(db);
+            if channel.pos > old.pos {
+                db.execute(
+                    "UPDATE channels SET pos = pos - 1 WHERE pos > ? AND pos <= ?",
+                    &[&(old.pos as i64), &(channel.pos as i64)]
+                ).unwrap();
+            } else if channel.pos < old.pos {
+                db.execute(
+                    "UPDATE channels SET pos = pos + 1 WHERE pos >= ? AND pos < ?",
+                    &[&(channel.pos as i64), &(old.pos as i64)]
+                ).unwrap();
+            }
             db.execute(
-                "UPDATE channels SET name = ? WHERE id = ?",
-                &[&channel.name, &(channel.id as i64)]
+                "UPDATE channels SET category = ?, name = ?, pos = ?, retention_max_age = ?, retention_max_count = ?,
+                slow_mode = ?, topic = ? WHERE id = ?",
+                &[
+                    &channel.category,
+                    &channel.name,
+                    &(channel.pos as i64),
+                    &channel.retention_max_age.map(|age| age as i64),
+                    &channel.retention_max_count.map(|count| count as i64),
+                    &(channel.slow_mode as i64),
+                    &channel.topic,
+                    &(channel.id as i64)
+                ]
             ).unwrap();
-            if !event.keep_overrides {
+            let mut channel = channel;
+            // Can't be changed later, since the existing messages couldn't be converted anyway
+            channel.encrypted = old.encrypted;
+            if event.keep_overrides {
+                channel.overrides = old.overrides;
+            } else {
                 insert_channel_overrides(db, channel.id, &channel.overrides);
+                broadcast_members(db, sessions);
             }
+            broadcast_moved_channels(db, &before, Some(channel.id), sessions);
 
             Reply::Broadcast(None, Packet::ChannelReceive(common::ChannelReceive {
-                inner: common::Channel {
-                    overrides:  channel.overrides,
-                    id: channel.id,
-                    name: channel.name
-                }
+                inner: channel
             }))
         },
         Packet::Command(cmd) => {
@@ -948,8 +2632,6 @@ fn handle_packet(
                 return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
             }
             if !has_perm(
-                config,
-                id,
                 calculate_permissions_by_user(db, id, None).unwrap(),
                 common::PERM_MANAGE_GROUPS
             ) {
@@ -978,12 +2660,14 @@ fn handle_packet(
                 &[&group.allow, &group.deny, &group.name, &(group.pos as i64),
                 &group.unassignable]
             ).unwrap();
+            let group_id = db.last_insert_rowid() as usize;
+            broadcast_members(db, sessions);
 
             Reply::Broadcast(None, Packet::GroupReceive(common::GroupReceive {
                 inner: common::Group {
                     allow: group.allow,
                     deny: group.deny,
-                    id: db.last_insert_rowid() as usize,
+                    id: group_id,
                     name: group.name,
                     pos: group.pos,
                     unassignable: group.unassignable
@@ -996,8 +2680,6 @@ fn handle_packet(
             rate_limit!(id, cheap);
 
             if !has_perm(
-                config,
-                id,
                 calculate_permissions_by_user(db, id, None).unwrap(),
                 common::PERM_MANAGE_GROUPS
             ) {
@@ -1020,6 +2702,7 @@ fn handle_packet(
                 "DELETE FROM groups WHERE id = ?",
                 &[&(group.id as i64)]
             ).unwrap();
+            broadcast_members(db, sessions);
 
             Reply::Broadcast(None, Packet::GroupDeleteReceive(common::GroupDeleteReceive {
                 inner: common::Group {
@@ -1042,8 +2725,6 @@ fn handle_packet(
                 return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
             }
             if !has_perm(
-                config,
-                id,
                 calculate_permissions_by_user(db, id, None).unwrap(),
                 common::PERM_MANAGE_GROUPS
             ) {
@@ -1081,6 +2762,7 @@ fn handle_packet(
                 &[&group.allow, &group.deny, &group.name, &(group.pos as i64), &group.unassignable,
                 &(group.id as i64)]
             ).unwrap();
+            broadcast_members(db, sessions);
 
             Reply::Broadcast(None, Packet::GroupReceive(common::GroupReceive {
                 inner: common::Group {
@@ -1094,22 +2776,154 @@ fn handle_packet(
                 new: true
             }))
         },
-        Packet::Login(login) => {
-            let mut stmt = db.prepare_cached(
-                "SELECT id, ban, bot, token, password FROM users WHERE name = ?"
-            ).unwrap();
-            let mut rows = stmt.query(&[&login.name]).unwrap();
-
-            if let Some(row) = rows.next() {
-                let row = row.unwrap();
+        Packet::InviteCreate(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
 
-                let row_id = row.get::<_, i64>(0) as usize;
-                let row_ban: bool = row.get(1);
-                let row_bot: bool = row.get(2);
-                let row_token:    String = row.get(3);
-                let row_password: String = row.get(4);
+            if !has_perm(
+                calculate_permissions_by_user(db, id, None).unwrap(),
+                common::PERM_CREATE_INVITES
+            ) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+            if event.max_uses == Some(0)
+                || event.max_age == Some(0)
+                || event.max_age.map_or(false, |age| age > common::LIMIT_INVITE_AGE) {
+                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+            }
 
-                if row_ban {
+            let now = Utc::now().timestamp();
+            // Dead invites shouldn't count towards the limit
+            db.execute(
+                "DELETE FROM invites WHERE author = ? AND (expires <= ? OR uses >= max_uses)",
+                &[&(id as i64), &now]
+            ).unwrap();
+            let count: i64 = db.query_row(
+                "SELECT COUNT(*) FROM invites WHERE author = ?",
+                &[&(id as i64)],
+                |row| row.get(0)
+            ).unwrap();
+            if count as usize >= INVITES_PER_USER {
+                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+            }
+
+            let mut code = attempt_or!(gen_token(), {
+                eprintln!("Failed to generate random invite");
+                return Reply::Close;
+            });
+            code.truncate(INVITE_LENGTH);
+            let expires = match event.max_age {
+                Some(age) => Some(unwrap_or_err!(now.checked_add(age as i64), common::ERR_LIMIT_REACHED)),
+                None => None
+            };
+            let max_uses = event.max_uses.map(|uses| uses as i64);
+
+            db.execute(
+                "INSERT INTO invites (author, code, expires, max_uses) VALUES (?, ?, ?, ?)",
+                &[&(id as i64), &code, &expires, &max_uses]
+            ).unwrap();
+
+            Reply::Reply(Packet::InviteReceive(common::InviteReceive {
+                inner: common::Invite {
+                    author: id,
+                    code: code,
+                    expires: expires,
+                    id: db.last_insert_rowid() as usize,
+                    max_uses: event.max_uses,
+                    uses: 0
+                }
+            }))
+        },
+        Packet::InviteList(_) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            db.execute(
+                "DELETE FROM invites WHERE expires <= ? OR uses >= max_uses",
+                &[&Utc::now().timestamp()]
+            ).unwrap();
+
+            let mut stmt = db.prepare_cached("SELECT * FROM invites WHERE author = ? ORDER BY id").unwrap();
+            let mut rows = stmt.query(&[&(id as i64)]).unwrap();
+
+            let writer = &mut sessions.get_mut(&conn_id).unwrap().writer;
+
+            while let Some(row) = rows.next() {
+                write(writer, Packet::InviteReceive(common::InviteReceive {
+                    inner: get_invite_by_fields(&row.unwrap())
+                }));
+            }
+            Reply::None
+        },
+        Packet::KeyPublish(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            if event.public_key.is_empty() || event.public_key.len() > common::LIMIT_PUBLIC_KEY {
+                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+            }
+
+            let mut user = get_user(db, id).unwrap();
+            let changed = user.public_key.as_ref() != Some(&event.public_key);
+            // Another device published its key, only replace it when asked to
+            if changed && user.public_key.is_some() && !event.rotate {
+                return Reply::Reply(Packet::Err(common::ERR_KEY_MISMATCH));
+            }
+
+            if event.rotate {
+                // Swapped together, or others would verify the new device's key against the old one's prekeys
+                let prekeys = unwrap_or_err!(event.prekeys, common::ERR_MISSING_FIELD);
+                if !is_valid_prekeys(&prekeys) {
+                    return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+                }
+                // Nobody could start a session with the old device's one-time prekeys anymore
+                db.execute("DELETE FROM one_time_prekeys WHERE user = ?", &[&(id as i64)]).unwrap();
+                store_prekeys(db, id, &prekeys);
+
+                let session = sessions.get_mut(&conn_id).unwrap();
+                write(&mut session.writer, Packet::PrekeyStatusReceive(common::PrekeyStatusReceive {
+                    published: true
+                }));
+            }
+            if !changed {
+                return Reply::None;
+            }
+
+            db.execute(
+                "UPDATE users SET public_key = ? WHERE id = ?",
+                &[&event.public_key, &(id as i64)]
+            ).unwrap();
+            user.public_key = Some(event.public_key);
+
+            Reply::Broadcast(None, Packet::UserReceive(common::UserReceive {
+                inner: user
+            }))
+        },
+        Packet::Login(login) => {
+            if is_locked(&throttle.ips, ip) {
+                return Reply::Reply(Packet::Err(common::ERR_LOGIN_LOCKED));
+            }
+            if let Some(ref device) = login.device {
+                if device.len() > common::LIMIT_TOKEN_LABEL {
+                    return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+                }
+            }
+            let device = login.device.unwrap_or_else(|| String::from("Unknown device"));
+
+            let mut stmt = db.prepare_cached(
+                "SELECT id, ban, bot, password FROM users WHERE name = ?"
+            ).unwrap();
+            let mut rows = stmt.query(&[&login.name]).unwrap();
+
+            if let Some(row) = rows.next() {
+                let row = row.unwrap();
+
+                let row_id = row.get::<_, i64>(0) as usize;
+                let row_ban: bool = row.get(1);
+                let row_bot: bool = row.get(2);
+                let row_password: String = row.get(3);
+
+                if row_ban {
                     let session = sessions.get_mut(&conn_id).unwrap();
                     write(&mut session.writer, Packet::Err(common::ERR_LOGIN_BANNED));
                     return Reply::Close;
@@ -1117,50 +2931,86 @@ fn handle_packet(
                 if row_bot != login.bot {
                     return Reply::Reply(Packet::Err(common::ERR_LOGIN_BOT));
                 }
-                if let Some(password) = login.password {
+                if is_locked(&throttle.accounts, &row_id) {
+                    return Reply::Reply(Packet::Err(common::ERR_LOGIN_LOCKED));
+                }
+                let (token_id, token) = if let Some(password) = login.password {
                     let valid = attempt_or!(bcrypt::verify(&password, &row_password), {
                         eprintln!("Failed to verify password");
                         return Reply::Close;
                     });
                     if !valid {
+                        record_login_failure(config, &mut throttle.accounts, row_id);
+                        record_login_failure(config, &mut throttle.ips, *ip);
                         return Reply::Reply(Packet::Err(common::ERR_LOGIN_INVALID));
                     }
-                    db.execute(
-                        "UPDATE users SET last_ip = ? WHERE id = ?",
-                        &[&ip.to_string(), &(row_id as i64)]
-                    ).unwrap();
-                    sessions
-                        .get_mut(&conn_id).unwrap()
-                        .id = Some(row_id);
-                    Reply::SendInitial(Box::new(Reply::Reply(Packet::LoginSuccess(common::LoginSuccess {
-                        created: false,
-                        id: row_id,
-                        token: row_token
-                    }))))
-                } else if let Some(token) = login.token {
-                    if token != row_token {
-                        return Reply::Reply(Packet::Err(common::ERR_LOGIN_INVALID));
+                    attempt_or!(create_token(db, token_key, row_id, &device, ip), {
+                        eprintln!("Failed to generate random token");
+                        return Reply::Close;
+                    })
+                } else if let Some(mut token) = login.token {
+                    let (token_id, issued, used) = match find_token(db, token_key, row_id, &token) {
+                        Some(some) => some,
+                        None => {
+                            record_login_failure(config, &mut throttle.ips, *ip);
+                            return Reply::Reply(Packet::Err(common::ERR_LOGIN_INVALID));
+                        }
+                    };
+                    let now = Utc::now().timestamp();
+
+                    if let Some(lifetime) = config.token_lifetime {
+                        // The config caps the lifetime, but the stored timestamps could still be anything
+                        let lifetime = lifetime as i64;
+                        if used.saturating_add(lifetime) <= now {
+                            db.execute("DELETE FROM tokens WHERE id = ?", &[&(token_id as i64)]).unwrap();
+                            return Reply::Reply(Packet::Err(common::ERR_LOGIN_INVALID));
+                        }
+                        if issued.saturating_add(lifetime / 2) <= now {
+                            // Halfway there, give the device a fresh one
+                            token = attempt_or!(gen_token(), {
+                                eprintln!("Failed to generate random token");
+                                return Reply::Close;
+                            });
+                            db.execute(
+                                "UPDATE tokens SET timestamp = ?, token = ? WHERE id = ?",
+                                &[&now, &hash_token(token_key, &token), &(token_id as i64)]
+                            ).unwrap();
+                        }
                     }
                     db.execute(
-                        "UPDATE users SET last_ip = ? WHERE id = ?",
-                        &[&ip.to_string(), &(row_id as i64)]
+                        "UPDATE tokens SET last_ip = ?, timestamp_used = ? WHERE id = ?",
+                        &[&ip.to_string(), &now, &(token_id as i64)]
                     ).unwrap();
-                    sessions
-                        .get_mut(&conn_id).unwrap()
-                        .id = Some(row_id);
-                    Reply::SendInitial(Box::new(Reply::Reply(Packet::LoginSuccess(common::LoginSuccess {
-                        created: false,
-                        id: row_id,
-                        token: token
-                    }))))
+                    (token_id, token)
                 } else {
-                    Reply::Reply(Packet::Err(common::ERR_MISSING_FIELD))
-                }
+                    return Reply::Reply(Packet::Err(common::ERR_MISSING_FIELD));
+                };
+                throttle.accounts.remove(&row_id);
+                db.execute(
+                    "UPDATE users SET last_ip = ? WHERE id = ?",
+                    &[&ip.to_string(), &(row_id as i64)]
+                ).unwrap();
+
+                let session = sessions.get_mut(&conn_id).unwrap();
+                session.id = Some(row_id);
+                session.token = Some(token_id);
+
+                Reply::SendInitial(Box::new(Reply::Reply(Packet::LoginSuccess(common::LoginSuccess {
+                    created: false,
+                    id: row_id,
+                    token: token
+                }))))
             } else if let Some(password) = login.password {
+                if config.registration == Registration::Closed {
+                    return Reply::Reply(Packet::Err(common::ERR_REGISTRATION_CLOSED));
+                }
                 if login.name.len() < config.limit_user_name_min
                     || login.name.len() > config.limit_user_name_max {
                     return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
                 }
+                if is_weak_password(config, &password) {
+                    return Reply::Reply(Packet::Err(common::ERR_PASSWORD_WEAK));
+                }
 
                 let count: i64 = db.query_row(
                     "SELECT COUNT(*) FROM users WHERE ban == 1 AND last_ip = ?",
@@ -1174,23 +3024,40 @@ fn handle_packet(
                     return Reply::Close;
                 }
 
+                if config.registration == Registration::InviteOnly {
+                    let invite = unwrap_or_err!(login.invite, common::ERR_INVITE_INVALID);
+                    let changed = db.execute(
+                        "UPDATE invites SET uses = uses + 1
+                        WHERE code = ? AND (expires IS NULL OR expires > ?) AND (max_uses IS NULL OR uses < max_uses)",
+                        &[&invite, &Utc::now().timestamp()]
+                    ).unwrap();
+
+                    if changed == 0 {
+                        record_login_failure(config, &mut throttle.ips, *ip);
+                        return Reply::Reply(Packet::Err(common::ERR_INVITE_INVALID));
+                    }
+                }
+
                 let password = attempt_or!(bcrypt::hash(&password, bcrypt::DEFAULT_COST), {
                     eprintln!("Failed to hash password");
                     return Reply::Close;
                 });
-                let token = attempt_or!(gen_token(), {
-                    eprintln!("Failed to generate random token");
-                    return Reply::Close;
-                });
 
                 db.execute(
-                    "INSERT INTO users (bot, last_ip, name, password, token) VALUES (?, ?, ?, ?, ?)",
-                    &[&login.bot, &ip.to_string(), &login.name, &password, &token]
+                    "INSERT INTO users (bot, last_ip, name, password, token) VALUES (?, ?, ?, ?, '')",
+                    &[&login.bot, &ip.to_string(), &login.name, &password]
                 ).unwrap();
 
                 let id = db.last_insert_rowid() as usize;
+                seed_owner(config, db);
+                broadcast_members(db, sessions);
+                let (token_id, token) = attempt_or!(create_token(db, token_key, id, &device, ip), {
+                    eprintln!("Failed to generate random token");
+                    return Reply::Close;
+                });
                 let session = sessions.get_mut(&conn_id).unwrap();
                 session.id = Some(id);
+                session.token = Some(token_id);
 
                 write(&mut session.writer, Packet::LoginSuccess(common::LoginSuccess {
                     created: true,
@@ -1204,7 +3071,10 @@ fn handle_packet(
                         bot: login.bot,
                         groups: Vec::new(),
                         id: id,
-                        name: login.name
+                        name: login.name,
+                        owner: is_owner(db, id),
+                        profile: common::Profile::default(),
+                        public_key: None
                     }
                 }))))
             } else {
@@ -1231,6 +3101,12 @@ fn handle_packet(
             }
             if let Some(current) = login.password_current {
                 let new = unwrap_or_err!(login.password_new, common::ERR_MISSING_FIELD);
+                if is_locked(&throttle.accounts, &id) {
+                    return Reply::Reply(Packet::Err(common::ERR_LOGIN_LOCKED));
+                }
+                if is_weak_password(config, &new) {
+                    return Reply::Reply(Packet::Err(common::ERR_PASSWORD_WEAK));
+                }
 
                 let mut stmt = db.prepare_cached("SELECT password FROM users WHERE id = ?").unwrap();
                 let mut rows = stmt.query(&[&(id as i64)]).unwrap();
@@ -1241,6 +3117,7 @@ fn handle_packet(
                     return Reply::Close;
                 });
                 if !valid {
+                    record_login_failure(config, &mut throttle.accounts, id);
                     return Reply::Reply(Packet::Err(common::ERR_LOGIN_INVALID));
                 }
 
@@ -1253,11 +3130,23 @@ fn handle_packet(
                 reset_token = true;
             }
             if reset_token {
-                let token = attempt_or!(gen_token(), {
+                // Log out every device, except the one asking for it
+                let label = sessions[&conn_id].token.and_then(|token| {
+                    db.query_row(
+                        "SELECT label FROM tokens WHERE id = ?",
+                        &[&(token as i64)],
+                        |row| row.get::<_, String>(0)
+                    ).ok()
+                }).unwrap_or_else(|| String::from("Unknown device"));
+
+                db.execute("DELETE FROM tokens WHERE user = ?", &[&(id as i64)]).unwrap();
+                let (token_id, token) = attempt_or!(create_token(db, token_key, id, &label, ip), {
                     eprintln!("Failed to generate random token");
                     return Reply::Close;
                 });
-                db.execute("UPDATE users SET token = ? WHERE id = ?", &[&token, &(id as i64)]).unwrap();
+                sessions.get_mut(&conn_id).unwrap().token = Some(token_id);
+                sessions.retain(|i, s| *i == conn_id || s.id != Some(id));
+
                 return Reply::Reply(Packet::LoginSuccess(common::LoginSuccess {
                     created: false,
                     id: id,
@@ -1266,12 +3155,13 @@ fn handle_packet(
             }
             Reply::None
         },
-        Packet::MessageCreate(msg) => {
+        Packet::MessageCreate(mut msg) => {
             let id = get_id!();
             rate_limit!(id, cheap);
 
-            if msg.text.len() < config.limit_message_min
-                || msg.text.len() > config.limit_message_max {
+            if (msg.text.len() < config.limit_message_min && msg.attachments.is_empty())
+                || msg.text.len() > config.limit_message_max
+                || msg.attachments.len() > common::LIMIT_ATTACHMENT_AMOUNT {
                 return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
             }
 
@@ -1279,28 +3169,75 @@ fn handle_packet(
             let timestamp = Utc::now().timestamp();
 
             if !has_perm(
-                config,
-                id,
                 calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
                 common::PERM_WRITE
             ) {
                 return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
             }
+            let slow_mode = channel.slow_mode != 0 && !has_perm(
+                calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
+                common::PERM_MANAGE_MESSAGES
+            );
+            if slow_mode {
+                if let Some(last) = users.get(&id).and_then(|user| user.slow_mode.get(&channel.id)) {
+                    let elapsed = last.elapsed().as_secs();
+                    if elapsed < channel.slow_mode {
+                        return Reply::Reply(Packet::RateLimited(channel.slow_mode - elapsed, Some(channel.id)));
+                    }
+                }
+            }
+
+            msg.attachments.sort_unstable();
+            msg.attachments.dedup();
+
+            if !msg.attachments.is_empty() {
+                let mut query = String::with_capacity(46 + 1 + 38);
+                query.push_str("SELECT COUNT(*) FROM attachments WHERE id IN (");
+                query.push_str(&from_list(&msg.attachments));
+                query.push_str(") AND author = ? AND message IS NULL");
+
+                let count: i64 = db.query_row(&query, &[&(id as i64)], |row| row.get(0)).unwrap();
+
+                if count as usize != msg.attachments.len() {
+                    return Reply::Reply(Packet::Err(common::ERR_UNKNOWN_ATTACHMENT));
+                }
+            }
 
             db.execute(
                 "INSERT INTO messages (author, channel, text, timestamp) VALUES (?, ?, ?, ?)",
                 &[&(id as i64), &(msg.channel as i64), &msg.text, &timestamp]
             ).unwrap();
+            let msg_id = db.last_insert_rowid() as usize;
+
+            if slow_mode {
+                users.entry(id).or_insert_with(UserSession::new).slow_mode.insert(channel.id, Instant::now());
+            }
+
+            if !msg.attachments.is_empty() {
+                let mut query = String::with_capacity(45 + 1 + 1);
+                query.push_str("UPDATE attachments SET message = ? WHERE id IN (");
+                query.push_str(&from_list(&msg.attachments));
+                query.push(')');
+
+                db.execute(&query, &[&(msg_id as i64)]).unwrap();
+            }
+
+            let msg = common::Message {
+                attachments: msg.attachments,
+                author: id,
+                channel: msg.channel,
+                id: msg_id,
+                reactions: HashMap::new(),
+                text: msg.text,
+                timestamp: timestamp,
+                timestamp_edit: None
+            };
+            if !channel.encrypted {
+                update_mentions(db, &channel, &msg, sessions);
+            }
 
             Reply::Broadcast(Some(channel.overrides), Packet::MessageReceive(common::MessageReceive {
-                inner: common::Message {
-                    author: id,
-                    channel: msg.channel,
-                    id: db.last_insert_rowid() as usize,
-                    text: msg.text,
-                    timestamp: timestamp,
-                    timestamp_edit: None
-                },
+                inner: msg,
                 new: true
             }))
         },
@@ -1312,18 +3249,13 @@ fn handle_packet(
             let channel = get_channel(db, msg.channel).unwrap();
 
             if msg.author != id && !has_perm(
-                config,
-                id,
                 calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
                 common::PERM_MANAGE_MESSAGES
             ) {
                 return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
             }
 
-            db.execute(
-                "DELETE FROM messages WHERE id = ?",
-                &[&(event.id as i64)]
-            ).unwrap();
+            delete_messages(db, &[event.id]);
 
             Reply::Broadcast(Some(channel.overrides), Packet::MessageDeleteReceive(common::MessageDeleteReceive {
                 id: event.id
@@ -1340,8 +3272,6 @@ fn handle_packet(
             let channel = unwrap_or_err!(get_channel(db, event.channel), common::ERR_UNKNOWN_CHANNEL);
 
             let has = has_perm(
-                config,
-                id,
                 calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
                 common::PERM_MANAGE_MESSAGES
             );
@@ -1368,12 +3298,7 @@ fn handle_packet(
                 // or the message doesn't exist.
                 // TODO Replace with a more generic error? Leave as is?
             }
-            let mut query = String::with_capacity(34 + 1 + 1);
-            query.push_str("DELETE FROM messages WHERE id IN (");
-            query.push_str(&list);
-            query.push(')');
-
-            db.execute(&query, &[]).unwrap();
+            delete_messages(db, &event.ids);
 
             for msg in event.ids {
                 let packet = Packet::MessageDeleteReceive(common::MessageDeleteReceive {
@@ -1381,7 +3306,6 @@ fn handle_packet(
                 });
                 write_broadcast(
                     Some(&channel.overrides),
-                    config,
                     db,
                     &packet,
                     None,
@@ -1399,8 +3323,6 @@ fn handle_packet(
                 return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
             }
             if !has_perm(
-                config,
-                id,
                 calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
                 common::PERM_READ
             ) {
@@ -1447,10 +3369,14 @@ fn handle_packet(
                 ]).unwrap();
             };
 
-            let writer = &mut sessions.get_mut(&conn_id).unwrap().writer;
-
+            let mut messages = Vec::new();
             while let Some(row) = rows.next() {
-                let msg = get_message_by_fields(&row.unwrap());
+                messages.push(get_message_by_fields(db, &row.unwrap()));
+            }
+            load_reactions(db, &mut messages);
+
+            let writer = &mut sessions.get_mut(&conn_id).unwrap().writer;
+            for msg in messages {
                 write(writer, Packet::MessageReceive(common::MessageReceive {
                     inner: msg,
                     new: false
@@ -1458,6 +3384,166 @@ fn handle_packet(
             }
             Reply::None
         },
+        Packet::MessagePin(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            let msg = unwrap_or_err!(get_message(db, event.id), common::ERR_UNKNOWN_MESSAGE);
+            let channel = get_channel(db, msg.channel).unwrap();
+
+            if !has_perm(
+                calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
+                common::PERM_MANAGE_MESSAGES
+            ) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+
+            let count: i64 = db.query_row(
+                "SELECT COUNT(*) FROM pins WHERE channel = ?",
+                &[&(msg.channel as i64)],
+                |row| row.get(0)
+            ).unwrap();
+
+            if count as usize >= common::LIMIT_BULK {
+                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+            }
+
+            let changed = db.execute(
+                "INSERT OR IGNORE INTO pins (channel, message, timestamp) VALUES (?, ?, ?)",
+                &[&(msg.channel as i64), &(event.id as i64), &Utc::now().timestamp()]
+            ).unwrap();
+
+            if changed == 0 {
+                return Reply::None;
+            }
+
+            Reply::Broadcast(Some(channel.overrides), Packet::PinReceive(common::PinReceive {
+                inner: msg,
+                new: true
+            }))
+        },
+        Packet::MessageSearch(params) => {
+            let id = get_id!();
+            // Results are capped by LIMIT_BULK, so searching doesn't need the expensive budget
+            rate_limit!(id, cheap);
+
+            if params.limit == 0 || params.limit > common::LIMIT_BULK
+                || params.query.len() > config.limit_message_max {
+                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+            }
+            let query = to_fts_query(&params.query);
+            if query.is_empty() {
+                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+            }
+
+            let channels = if let Some(channel) = params.channel {
+                let channel = unwrap_or_err!(get_channel(db, channel), common::ERR_UNKNOWN_CHANNEL);
+                if !has_perm(
+                    calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
+                    common::PERM_READ
+                ) {
+                    return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+                }
+                if channel.encrypted {
+                    Vec::new()
+                } else {
+                    vec![channel.id]
+                }
+            } else {
+                let mut stmt = db.prepare_cached("SELECT * FROM channels").unwrap();
+                let mut rows = stmt.query(&[]).unwrap();
+
+                let mut channels = Vec::new();
+                while let Some(row) = rows.next() {
+                    let channel = get_channel_by_fields(db, &row.unwrap());
+                    if !channel.encrypted && has_perm(
+                        calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
+                        common::PERM_READ
+                    ) {
+                        channels.push(channel.id);
+                    }
+                }
+                channels
+            };
+            if channels.is_empty() {
+                return Reply::None;
+            }
+
+            let mut query_sql = String::with_capacity(145 + 1 + 199);
+            query_sql.push_str(
+                "SELECT messages.* FROM messages_fts
+                JOIN messages ON messages.id = messages_fts.docid
+                WHERE messages_fts MATCH ?1 AND messages.channel IN ("
+            );
+            query_sql.push_str(&from_list(&channels));
+            query_sql.push_str(
+                ") AND (?2 IS NULL OR messages.author = ?2)
+                AND (?3 IS NULL OR messages.timestamp < ?3)
+                AND (?4 IS NULL OR messages.timestamp > ?4)
+                ORDER BY messages.timestamp DESC
+                LIMIT ?5"
+            );
+
+            let author = params.author.map(|author| author as i64);
+
+            let mut stmt = db.prepare(&query_sql).unwrap();
+            let mut rows = stmt.query(&[
+                &query,
+                &author,
+                &params.before,
+                &params.after,
+                &(params.limit as i64)
+            ]).unwrap();
+
+            let mut messages = Vec::new();
+            while let Some(row) = rows.next() {
+                let row = match row {
+                    Ok(ok) => ok,
+                    Err(err) => {
+                        eprintln!("Failed to search messages: {}", err);
+                        break;
+                    }
+                };
+                messages.push(get_message_by_fields(db, &row));
+            }
+            load_reactions(db, &mut messages);
+
+            let writer = &mut sessions.get_mut(&conn_id).unwrap().writer;
+            for msg in messages {
+                write(writer, Packet::SearchReceive(common::SearchReceive {
+                    inner: msg
+                }));
+            }
+            Reply::None
+        },
+        Packet::MessageUnpin(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            let msg = unwrap_or_err!(get_message(db, event.id), common::ERR_UNKNOWN_MESSAGE);
+            let channel = get_channel(db, msg.channel).unwrap();
+
+            if !has_perm(
+                calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
+                common::PERM_MANAGE_MESSAGES
+            ) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+
+            let changed = db.execute(
+                "DELETE FROM pins WHERE message = ?",
+                &[&(event.id as i64)]
+            ).unwrap();
+
+            if changed == 0 {
+                return Reply::None;
+            }
+
+            Reply::Broadcast(Some(channel.overrides), Packet::PinDeleteReceive(common::PinDeleteReceive {
+                channel: msg.channel,
+                id: event.id
+            }))
+        },
         Packet::MessageUpdate(event) => {
             let id = get_id!();
             rate_limit!(id, cheap);
@@ -1479,18 +3565,198 @@ fn handle_packet(
                 &[&event.text, &(event.id as i64)]
             ).unwrap();
 
+            let msg = common::Message {
+                attachments: msg.attachments,
+                author: id,
+                channel: msg.channel,
+                id: event.id,
+                reactions: msg.reactions,
+                text: event.text,
+                timestamp: msg.timestamp,
+                timestamp_edit: Some(timestamp)
+            };
+            if !channel.encrypted {
+                update_mentions(db, &channel, &msg, sessions);
+            }
+
             Reply::Broadcast(Some(channel.overrides), Packet::MessageReceive(common::MessageReceive {
-                inner: common::Message {
-                    author: id,
-                    channel: msg.channel,
-                    id: event.id,
-                    text: event.text,
-                    timestamp: msg.timestamp,
-                    timestamp_edit: Some(timestamp)
-                },
+                inner: msg,
                 new: true
             }))
         },
+        Packet::OwnershipTransfer(event) => {
+            let id = get_id!();
+            rate_limit!(id, expensive);
+
+            if !is_owner(db, id) || event.id == id {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+            if is_locked(&throttle.accounts, &id) {
+                return Reply::Reply(Packet::Err(common::ERR_LOGIN_LOCKED));
+            }
+            let mut user = unwrap_or_err!(get_user(db, event.id), common::ERR_UNKNOWN_USER);
+            if user.ban {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+
+            let password: String = db.query_row(
+                "SELECT password FROM users WHERE id = ?",
+                &[&(id as i64)],
+                |row| row.get(0)
+            ).unwrap();
+            let valid = attempt_or!(bcrypt::verify(&event.password, &password), {
+                eprintln!("Failed to verify password");
+                return Reply::Close;
+            });
+            if !valid {
+                record_login_failure(config, &mut throttle.accounts, id);
+                return Reply::Reply(Packet::Err(common::ERR_LOGIN_INVALID));
+            }
+
+            db.execute("UPDATE users SET owner = 1 WHERE id = ?", &[&(event.id as i64)]).unwrap();
+            user.owner = true;
+
+            if !event.keep {
+                db.execute("UPDATE users SET owner = 0 WHERE id = ?", &[&(id as i64)]).unwrap();
+                write_broadcast(
+                    None,
+                    db,
+                    &Packet::UserReceive(common::UserReceive {
+                        inner: get_user(db, id).unwrap()
+                    }),
+                    None,
+                    sessions
+                );
+            }
+
+            Reply::Broadcast(None, Packet::UserReceive(common::UserReceive {
+                inner: user
+            }))
+        },
+        Packet::PinList(params) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            let channel = unwrap_or_err!(get_channel(db, params.channel), common::ERR_UNKNOWN_CHANNEL);
+            if !has_perm(
+                calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
+                common::PERM_READ
+            ) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+
+            let mut stmt = db.prepare_cached(
+                "SELECT messages.* FROM pins
+                JOIN messages ON messages.id = pins.message
+                WHERE pins.channel = ?
+                ORDER BY pins.timestamp"
+            ).unwrap();
+            let mut rows = stmt.query(&[&(params.channel as i64)]).unwrap();
+
+            let mut messages = Vec::new();
+            while let Some(row) = rows.next() {
+                messages.push(get_message_by_fields(db, &row.unwrap()));
+            }
+            load_reactions(db, &mut messages);
+
+            let writer = &mut sessions.get_mut(&conn_id).unwrap().writer;
+            for msg in messages {
+                write(writer, Packet::PinReceive(common::PinReceive {
+                    inner: msg,
+                    new: false
+                }));
+            }
+            Reply::None
+        },
+        Packet::PMAck(event) => {
+            // Not rate limited, one is sent for every queued message at login and it only deletes the user's own rows
+            let id = get_id!();
+
+            db.execute(
+                "DELETE FROM pm_queue WHERE id = ? AND recipient = ?",
+                &[&(event.id as i64), &(id as i64)]
+            ).unwrap();
+
+            Reply::None
+        },
+        Packet::PrekeyPublish(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            if !is_valid_prekeys(&event) {
+                return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+            }
+
+            let identity: Option<Vec<u8>> = db.query_row(
+                "SELECT identity FROM prekeys WHERE user = ?",
+                &[&(id as i64)],
+                |row| row.get(0)
+            ).ok();
+            if let Some(identity) = identity {
+                // Belongs to another device, which only a KeyPublish with `rotate` may replace
+                if identity != event.inner.identity {
+                    return Reply::Reply(Packet::Err(common::ERR_KEY_MISMATCH));
+                }
+            }
+
+            store_prekeys(db, id, &event);
+
+            // The client keeps the private halves of replaced prekeys until now, in case this was refused
+            Reply::Reply(Packet::PrekeyStatusReceive(common::PrekeyStatusReceive {
+                published: true
+            }))
+        },
+        Packet::PrekeyRequest(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            if get_user(db, event.user).is_none() {
+                return Reply::Reply(Packet::Err(common::ERR_UNKNOWN_USER));
+            }
+
+            let inner = db.query_row(
+                "SELECT identity, identity_signature, signed, signed_signature FROM prekeys WHERE user = ?",
+                &[&(event.user as i64)],
+                |row| common::Prekeys {
+                    identity: row.get(0),
+                    identity_signature: row.get(1),
+                    signed: row.get(2),
+                    signed_signature: row.get(3)
+                }
+            ).ok();
+
+            let mut one_time = None;
+            if inner.is_some() {
+                // Each one-time key is handed out once
+                if let Ok((key_id, key)) = db.query_row(
+                    "SELECT id, key FROM one_time_prekeys WHERE user = ? ORDER BY id LIMIT 1",
+                    &[&(event.user as i64)],
+                    |row| (row.get::<_, i64>(0), row.get::<_, Vec<u8>>(1))
+                ) {
+                    db.execute("DELETE FROM one_time_prekeys WHERE id = ?", &[&key_id]).unwrap();
+                    one_time = Some(key);
+
+                    let left: i64 = db.query_row(
+                        "SELECT COUNT(*) FROM one_time_prekeys WHERE user = ?",
+                        &[&(event.user as i64)],
+                        |row| row.get(0)
+                    ).unwrap();
+                    // Only once, offline users are asked again when they log in
+                    if left as usize == PREKEYS_LOW {
+                        let packet = Packet::PrekeyStatusReceive(common::PrekeyStatusReceive {
+                            published: false
+                        });
+                        write_broadcast(None, db, &packet, Some(event.user), sessions);
+                    }
+                }
+            }
+
+            Reply::Reply(Packet::PrekeyReceive(common::PrekeyReceive {
+                inner: inner,
+                one_time: one_time,
+                user: event.user
+            }))
+        },
         Packet::PrivateMessage(msg) => {
             let id = get_id!();
             rate_limit!(id, cheap);
@@ -1509,17 +3775,219 @@ fn handle_packet(
                 return Reply::Reply(Packet::Err(common::ERR_UNKNOWN_USER));
             }
 
-            Reply::Private(msg.recipient, Packet::PMReceive(common::PMReceive {
+            let timestamp = Utc::now().timestamp();
+            let online = sessions.values().any(|session| session.id == Some(msg.recipient));
+
+            if online {
+                let packet = Packet::PMReceive(common::PMReceive {
+                    author: id,
+                    id: None,
+                    text: msg.text,
+                    timestamp: timestamp
+                });
+                write_broadcast(None, db, &packet, Some(msg.recipient), sessions);
+            } else {
+                let (size, size_sender): (i64, i64) = db.query_row(
+                    "SELECT COALESCE(SUM(LENGTH(text)), 0), COALESCE(SUM(CASE WHEN author = ? THEN LENGTH(text) END), 0)
+                    FROM pm_queue WHERE recipient = ?",
+                    &[&(id as i64), &(msg.recipient as i64)],
+                    |row| (row.get(0), row.get(1))
+                ).unwrap();
+
+                if size as usize + msg.text.len() > config.pm_queue_max_size
+                    || size_sender as usize + msg.text.len() > config.pm_queue_max_size_per_sender {
+                    return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+                }
+
+                db.execute(
+                    "INSERT INTO pm_queue (author, recipient, text, timestamp) VALUES (?, ?, ?, ?)",
+                    &[&(id as i64), &(msg.recipient as i64), &msg.text, &timestamp]
+                ).unwrap();
+            }
+
+            Reply::Reply(Packet::PMDeliveryReceive(common::PMDeliveryReceive {
+                delivered: online,
+                recipient: msg.recipient
+            }))
+        },
+        Packet::ProfileUpdate(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            let profile = event.inner;
+            {
+                let fields = [
+                    (&profile.bio, config.limit_profile_bio_max),
+                    (&profile.display_name, config.limit_profile_display_name_max),
+                    (&profile.pronouns, config.limit_profile_pronouns_max),
+                    (&profile.timezone, config.limit_profile_timezone_max)
+                ];
+                for &(field, max) in &fields {
+                    if let Some(ref field) = *field {
+                        if field.is_empty() || field.len() > max {
+                            return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+                        }
+                    }
+                }
+            }
+
+            db.execute(
+                "UPDATE users SET profile_bio = ?, profile_display_name = ?, profile_pronouns = ?, profile_timezone = ?
+                WHERE id = ?",
+                &[&profile.bio, &profile.display_name, &profile.pronouns, &profile.timezone, &(id as i64)]
+            ).unwrap();
+
+            Reply::Broadcast(None, Packet::UserReceive(common::UserReceive {
+                inner: get_user(db, id).unwrap()
+            }))
+        },
+        Packet::ReactionAdd(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            if !is_valid_reaction(&event.emoji) {
+                return Reply::Reply(Packet::Err(common::ERR_INVALID_REACTION));
+            }
+
+            let msg = unwrap_or_err!(get_message(db, event.message), common::ERR_UNKNOWN_MESSAGE);
+            let channel = get_channel(db, msg.channel).unwrap();
+
+            if !has_perm(
+                calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
+                common::PERM_WRITE
+            ) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+
+            let changed = db.execute(
+                "INSERT OR IGNORE INTO reactions (author, emoji, message) VALUES (?, ?, ?)",
+                &[&(id as i64), &event.emoji, &(event.message as i64)]
+            ).unwrap();
+
+            if changed == 0 {
+                return Reply::None;
+            }
+
+            Reply::Broadcast(Some(channel.overrides), Packet::ReactionReceive(common::ReactionReceive {
+                author: id,
+                channel: msg.channel,
+                emoji: event.emoji,
+                message: event.message
+            }))
+        },
+        Packet::ReactionRemove(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            let msg = unwrap_or_err!(get_message(db, event.message), common::ERR_UNKNOWN_MESSAGE);
+            let channel = get_channel(db, msg.channel).unwrap();
+
+            if !has_perm(
+                calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
+                common::PERM_WRITE
+            ) {
+                return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
+            }
+
+            let changed = db.execute(
+                "DELETE FROM reactions WHERE author = ? AND emoji = ? AND message = ?",
+                &[&(id as i64), &event.emoji, &(event.message as i64)]
+            ).unwrap();
+
+            if changed == 0 {
+                return Reply::None;
+            }
+
+            Reply::Broadcast(Some(channel.overrides), Packet::ReactionDeleteReceive(common::ReactionDeleteReceive {
                 author: id,
-                text: msg.text
+                channel: msg.channel,
+                emoji: event.emoji,
+                message: event.message
             }))
         },
+        Packet::StatusUpdate(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            if let Some(ref text) = event.text {
+                if text.is_empty() || text.len() > common::LIMIT_STATUS {
+                    return Reply::Reply(Packet::Err(common::ERR_LIMIT_REACHED));
+                }
+            }
+
+            db.execute(
+                "UPDATE users SET status_dnd = ?, status_text = ? WHERE id = ?",
+                &[&event.dnd, &event.text, &(id as i64)]
+            ).unwrap();
+
+            Reply::Broadcast(None, Packet::PresenceReceive(common::PresenceReceive {
+                dnd: event.dnd,
+                id: id,
+                presence: get_presence(sessions, id),
+                status: event.text
+            }))
+        },
+        Packet::TokenList(_) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            let current = sessions[&conn_id].token;
+
+            let mut stmt = db.prepare_cached(
+                "SELECT id, label, last_ip, timestamp, timestamp_used FROM tokens
+                WHERE user = ? ORDER BY timestamp_used DESC"
+            ).unwrap();
+            let mut rows = stmt.query(&[&(id as i64)]).unwrap();
+
+            let writer = &mut sessions.get_mut(&conn_id).unwrap().writer;
+
+            while let Some(row) = rows.next() {
+                let row = row.unwrap();
+                let token_id = row.get::<_, i64>(0) as usize;
+
+                write(writer, Packet::TokenReceive(common::TokenReceive {
+                    inner: common::Token {
+                        current: current == Some(token_id),
+                        id: token_id,
+                        label: row.get(1),
+                        last_ip: row.get(2),
+                        timestamp: row.get(3),
+                        timestamp_used: row.get(4)
+                    }
+                }));
+            }
+            Reply::None
+        },
+        Packet::TokenRevoke(event) => {
+            let id = get_id!();
+            rate_limit!(id, cheap);
+
+            let changed = db.execute(
+                "DELETE FROM tokens WHERE id = ? AND user = ?",
+                &[&(event.id as i64), &(id as i64)]
+            ).unwrap();
+
+            if changed == 0 {
+                return Reply::Reply(Packet::Err(common::ERR_UNKNOWN_TOKEN));
+            }
+
+            {
+                let session = sessions.get_mut(&conn_id).unwrap();
+                if session.token != Some(event.id) {
+                    write(&mut session.writer, Packet::TokenDeleteReceive(common::TokenDeleteReceive {
+                        id: event.id
+                    }));
+                }
+            }
+            // Kick whoever is still using it
+            sessions.retain(|_, s| s.token != Some(event.id));
+
+            Reply::None
+        },
         Packet::Typing(event) => {
             let id = get_id!();
             let channel = unwrap_or_err!(get_channel(db, event.channel), common::ERR_UNKNOWN_CHANNEL);
             if !has_perm(
-                config,
-                id,
                 calculate_permissions_by_user(db, id, Some(&channel.overrides)).unwrap(),
                 common::PERM_WRITE
             ) {
@@ -1539,11 +4007,9 @@ fn handle_packet(
             let old = unwrap_or_err!(get_user(db, event.id), common::ERR_UNKNOWN_USER);
             if let Some(ban) = event.ban {
                 if event.id == id
-                    || event.id == config.owner_id
+                    || old.owner
                     || !has_perm(
-                    config,
-                    id,
-                    calculate_permissions(db, user.bot, &user.groups, None),
+                    calculate_permissions(db, user.bot, user.owner, &user.groups, None),
                     common::PERM_BAN
                 ) {
                     return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION));
@@ -1554,6 +4020,7 @@ fn handle_packet(
                     &[&ban, &(event.id as i64)]
                 ).unwrap();
                 sessions.retain(|_, s| s.id != Some(event.id));
+                broadcast_members(db, sessions);
 
                 Reply::Broadcast(None, Packet::UserReceive(common::UserReceive {
                     inner: common::User {
@@ -1561,14 +4028,15 @@ fn handle_packet(
                         bot:  old.bot,
                         groups: old.groups,
                         id:   old.id,
-                        name: old.name
+                        name: old.name,
+                        owner: old.owner,
+                        profile: old.profile,
+                        public_key: old.public_key
                     }
                 }))
             } else if let Some(mut groups) = event.groups {
                 if !has_perm(
-                    config,
-                    id,
-                    calculate_permissions(db, user.bot, &user.groups, None),
+                    calculate_permissions(db, user.bot, user.owner, &user.groups, None),
                     common::PERM_ASSIGN_GROUPS
                 ) {
                     return Reply::Reply(Packet::Err(common::ERR_MISSING_PERMISSION))
@@ -1590,9 +4058,7 @@ fn handle_packet(
                 }
 
                 let correct = if has_perm(
-                    config,
-                    id,
-                    calculate_permissions(db, user.bot, &user.groups, None),
+                    calculate_permissions(db, user.bot, user.owner, &user.groups, None),
                     common::PERM_MANAGE_GROUPS
                 ) {
                     let mut ok = true;
@@ -1627,6 +4093,7 @@ fn handle_packet(
                     "UPDATE users SET groups = ? WHERE id = ?",
                     &[&from_list(&groups), &(event.id as i64)]
                 ).unwrap();
+                broadcast_members(db, sessions);
 
                 Reply::Broadcast(None, Packet::UserReceive(common::UserReceive {
                     inner: common::User {
@@ -1634,7 +4101,10 @@ fn handle_packet(
                         bot: old.bot,
                         groups: groups,
                         id: event.id,
-                        name: old.name
+                        name: old.name,
+                        owner: old.owner,
+                        profile: old.profile,
+                        public_key: old.public_key
                     }
                 }))
             } else {